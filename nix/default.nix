@@ -35,7 +35,6 @@ in
       individualCrateArgs
       // {
         pname = "keypad";
-        cargoExtraARgs = "-p cloudmqtt";
         src = fileSetForCrate ./..;
       }
     );
@@ -1,32 +1,105 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use miette::IntoDiagnostic;
+use miette::WrapErr;
+
+/// Receives the errors of actions that finish in the background, like HTTP requests
+pub type ActionErrors = tokio::sync::mpsc::UnboundedSender<miette::Error>;
+
 #[derive(Clone, Debug)]
 pub enum Action {
     ToggleBlinking,
     ToggleBlinkingAlternativeColor,
-    PublishMqtt { topic: String, payload: String },
+    PublishMqtt {
+        topic: String,
+        payload: String,
+        qos: crate::mqtt::Qos,
+        retain: bool,
+    },
+    SwitchPage {
+        name: String,
+    },
+    PushPage {
+        name: String,
+    },
+    PopPage,
+    Exec {
+        command: String,
+        args: Vec<String>,
+        env: BTreeMap<String, String>,
+        timeout: Duration,
+    },
+    Http {
+        method: String,
+        url: String,
+        headers: BTreeMap<String, String>,
+        body: Option<String>,
+        timeout: Duration,
+    },
+    StartEffect(crate::keypad::animation::PadEffect),
+    StopEffect,
+    SetBrightness(u8),
+    BrightnessUp,
+    BrightnessDown,
 }
 
-impl From<&crate::config::OnPressAction> for Action {
-    fn from(value: &crate::config::OnPressAction) -> Self {
+impl From<&crate::config::ActionConfig> for Action {
+    fn from(value: &crate::config::ActionConfig) -> Self {
         match value {
-            crate::config::OnPressAction::ToggleBlinking => Action::ToggleBlinking,
-            crate::config::OnPressAction::ToggleBlinkingAlternativeColor => {
+            crate::config::ActionConfig::ToggleBlinking => Action::ToggleBlinking,
+            crate::config::ActionConfig::ToggleBlinkingAlternativeColor => {
                 Action::ToggleBlinkingAlternativeColor
             }
-            crate::config::OnPressAction::Publish { topic, payload } => Action::PublishMqtt {
+            crate::config::ActionConfig::Publish {
+                topic,
+                payload,
+                qos,
+                retain,
+            } => Action::PublishMqtt {
                 topic: topic.to_string(),
                 payload: payload.to_string(),
+                qos: *qos,
+                retain: *retain,
             },
-        }
-    }
-}
-
-impl From<&crate::config::OnReleaseAction> for Action {
-    fn from(value: &crate::config::OnReleaseAction) -> Self {
-        match value {
-            crate::config::OnReleaseAction::Publish { topic, payload } => Action::PublishMqtt {
-                topic: topic.to_string(),
-                payload: payload.to_string(),
+            crate::config::ActionConfig::SwitchPage { name } => Action::SwitchPage {
+                name: name.to_string(),
+            },
+            crate::config::ActionConfig::PushPage { name } => Action::PushPage {
+                name: name.to_string(),
+            },
+            crate::config::ActionConfig::PopPage => Action::PopPage,
+            crate::config::ActionConfig::Exec {
+                command,
+                args,
+                env,
+                timeout,
+            } => Action::Exec {
+                command: command.to_string(),
+                args: args.clone(),
+                env: env.clone(),
+                timeout: timeout.unwrap_or(crate::konst::DEFAULT_EXEC_TIMEOUT),
+            },
+            crate::config::ActionConfig::Http {
+                method,
+                url,
+                headers,
+                body,
+                timeout,
+            } => Action::Http {
+                method: method.to_string(),
+                url: url.to_string(),
+                headers: headers.clone(),
+                body: body.clone(),
+                timeout: timeout.unwrap_or(crate::konst::DEFAULT_HTTP_TIMEOUT),
             },
+            crate::config::ActionConfig::StartEffect(effect) => {
+                Action::StartEffect(crate::keypad::animation::PadEffect::from(effect))
+            }
+            crate::config::ActionConfig::StopEffect => Action::StopEffect,
+            crate::config::ActionConfig::SetBrightness(level) => Action::SetBrightness(*level),
+            crate::config::ActionConfig::BrightnessUp => Action::BrightnessUp,
+            crate::config::ActionConfig::BrightnessDown => Action::BrightnessDown,
         }
     }
 }
@@ -35,7 +108,7 @@ impl Action {
     pub async fn execute(
         &self,
         key_state: &mut crate::keypad::KeyState,
-        mqtt_client: &cloudmqtt::CloudmqttClient,
+        mqtt_client: &crate::mqtt::Client,
     ) -> Result<(), miette::Error> {
         match self {
             Action::ToggleBlinking => {
@@ -50,12 +123,206 @@ impl Action {
                 Ok(())
             }
 
-            Action::PublishMqtt { topic, payload } => {
+            Action::PublishMqtt {
+                topic,
+                payload,
+                qos,
+                retain,
+            } => {
+                let variables = key_state.template_variables();
+                let topic = crate::template::render(topic, &variables)?;
+                let payload = crate::template::render(payload, &variables)?;
+
                 tracing::info!(?topic, ?payload, "Action: Publishing");
-                mqtt_client.publish(payload, topic).await;
+                crate::mqtt::publish(mqtt_client, topic, payload, *qos, *retain).await;
                 Ok(())
             }
+
+            Action::SwitchPage { name } => {
+                tracing::info!(?name, "Action: Switch page");
+                key_state.request_page(crate::keypad::PageRequest::Switch(name.to_string()));
+                Ok(())
+            }
+
+            Action::PushPage { name } => {
+                tracing::info!(?name, "Action: Push page");
+                key_state.request_page(crate::keypad::PageRequest::Push(name.to_string()));
+                Ok(())
+            }
+
+            Action::PopPage => {
+                tracing::info!("Action: Pop page");
+                key_state.request_page(crate::keypad::PageRequest::Pop);
+                Ok(())
+            }
+
+            Action::Exec {
+                command,
+                args,
+                env,
+                timeout,
+            } => {
+                tracing::info!(?command, ?args, "Action: Executing command");
+                let child = spawn_command(command, args, env)?;
+
+                // Do not block the keypad while the command is running
+                tokio::spawn(report_command(command.to_string(), child, *timeout));
+                Ok(())
+            }
+
+            Action::Http {
+                method,
+                url,
+                headers,
+                body,
+                timeout,
+            } => {
+                tracing::info!(?method, ?url, "Action: Sending HTTP request");
+                let (method, url, headers, body, timeout) = (
+                    method.to_string(),
+                    url.to_string(),
+                    headers.clone(),
+                    body.clone(),
+                    *timeout,
+                );
+
+                // Do not block the keypad while waiting for the response, errors are reported
+                // back to the main loop
+                let action_errors = key_state.action_errors();
+                tokio::spawn(async move {
+                    let result =
+                        http_request(&method, &url, &headers, body.as_deref(), timeout).await;
+                    if let Err(error) = result {
+                        match action_errors {
+                            Some(action_errors) => {
+                                if let Err(unreported) = action_errors.send(error) {
+                                    tracing::error!(error = ?unreported.0, "HTTP request failed");
+                                }
+                            }
+                            None => tracing::error!(?error, "HTTP request failed"),
+                        }
+                    }
+                });
+                Ok(())
+            }
+
+            Action::StartEffect(effect) => {
+                tracing::info!(?effect, "Action: Start effect");
+                key_state.request_effect(crate::keypad::animation::EffectRequest::Start(
+                    effect.clone(),
+                ));
+                Ok(())
+            }
+
+            Action::StopEffect => {
+                tracing::info!("Action: Stop effect");
+                key_state.request_effect(crate::keypad::animation::EffectRequest::Stop);
+                Ok(())
+            }
+
+            Action::SetBrightness(level) => {
+                tracing::info!(?level, "Action: Set brightness");
+                key_state.request_brightness(crate::brightness::BrightnessRequest::Set(*level));
+                Ok(())
+            }
+
+            Action::BrightnessUp => {
+                tracing::info!("Action: Brightness up");
+                key_state.request_brightness(crate::brightness::BrightnessRequest::Up);
+                Ok(())
+            }
+
+            Action::BrightnessDown => {
+                tracing::info!("Action: Brightness down");
+                key_state.request_brightness(crate::brightness::BrightnessRequest::Down);
+                Ok(())
+            }
+        }
+    }
+}
+
+static HTTP_CLIENT: std::sync::LazyLock<reqwest::Client> =
+    std::sync::LazyLock::new(reqwest::Client::new);
+
+async fn http_request(
+    method: &str,
+    url: &str,
+    headers: &BTreeMap<String, String>,
+    body: Option<&str>,
+    timeout: Duration,
+) -> Result<(), miette::Error> {
+    let method = reqwest::Method::from_bytes(method.as_bytes())
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Invalid HTTP method '{method}'"))?;
+
+    let mut request = HTTP_CLIENT.request(method.clone(), url).timeout(timeout);
+    for (name, value) in headers.iter() {
+        request = request.header(name, value);
+    }
+    if let Some(body) = body {
+        request = request.body(body.to_string());
+    }
+
+    let response = request
+        .send()
+        .await
+        .into_diagnostic()
+        .wrap_err_with(|| format!("HTTP request {method} {url} failed"))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        miette::bail!("HTTP request {method} {url} failed with status {status}: {body}");
+    }
+
+    tracing::debug!(%method, url, %status, "HTTP request succeeded");
+    Ok(())
+}
+
+fn spawn_command(
+    command: &str,
+    args: &[String],
+    env: &BTreeMap<String, String>,
+) -> Result<tokio::process::Child, miette::Error> {
+    tokio::process::Command::new(command)
+        .args(args)
+        .envs(env)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to execute '{command}'"))
+}
+
+async fn report_command(command: String, child: tokio::process::Child, timeout: Duration) {
+    match wait_for_command(&command, child, timeout).await {
+        Ok(output) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if output.status.success() {
+                tracing::info!(?command, status = %output.status, %stdout, %stderr, "Command finished");
+            } else {
+                tracing::error!(?command, status = %output.status, %stdout, %stderr, "Command failed");
+            }
         }
+        Err(error) => tracing::error!(?error, "Command failed"),
+    }
+}
+
+/// Wait for the command to exit and capture its output, killing it once the timeout expires
+async fn wait_for_command(
+    command: &str,
+    child: tokio::process::Child,
+    timeout: Duration,
+) -> Result<std::process::Output, miette::Error> {
+    match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(result) => result
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to wait for '{command}'")),
+        // Dropping the child kills it
+        Err(_elapsed) => miette::bail!("'{command}' timed out after {timeout:?} and was killed"),
     }
 }
 
@@ -64,8 +331,291 @@ pub(crate) struct ControlPacket {
     pub(crate) actions: Vec<ControlAction>,
 }
 
-#[derive(Debug, serde::Deserialize)]
+impl ControlPacket {
+    /// Parse a JSON control packet, whose colors can refer to entries of the palette
+    pub(crate) fn parse(
+        payload: &[u8],
+        palette: &BTreeMap<String, crate::util::Rgb>,
+    ) -> Result<Self, serde_json::Error> {
+        crate::util::with_palette(palette.clone(), || serde_json::from_slice(payload))
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
 pub(crate) enum ControlAction {
     ToggleBlinking,
     ToggleBlinkingAlternativeColor,
+    SetBlinking(bool),
+    SetBlinkingAlternativeColor(bool),
+    /// Replace the colors of the key, keeping those that are not passed
+    SetColor {
+        #[serde(default)]
+        released: Option<crate::util::Rgb>,
+        #[serde(default)]
+        pressed: Option<crate::util::Rgb>,
+        #[serde(default)]
+        alternative: Option<crate::util::Rgb>,
+    },
+    /// Undo all changes made by control actions
+    ResetToConfig,
+    /// Show a color for the passed duration, regardless of the state of the key
+    Flash {
+        color: crate::util::Rgb,
+        #[serde(with = "humantime_serde")]
+        duration: std::time::Duration,
+    },
+    SwitchPage {
+        name: String,
+    },
+    PushPage {
+        name: String,
+    },
+    PopPage,
+    /// Set the brightness of the keypad in percent
+    SetBrightness(u8),
+    BrightnessUp,
+    BrightnessDown,
+}
+
+impl ControlAction {
+    /// The page change requested by this action, which applies to the keypad instead of keys
+    pub(crate) fn page_request(&self) -> Option<crate::keypad::PageRequest> {
+        match self {
+            ControlAction::SwitchPage { name } => {
+                Some(crate::keypad::PageRequest::Switch(name.to_string()))
+            }
+            ControlAction::PushPage { name } => {
+                Some(crate::keypad::PageRequest::Push(name.to_string()))
+            }
+            ControlAction::PopPage => Some(crate::keypad::PageRequest::Pop),
+            _ => None,
+        }
+    }
+
+    /// The brightness change requested by this action, which applies to the keypad instead of keys
+    pub(crate) fn brightness_request(&self) -> Option<crate::brightness::BrightnessRequest> {
+        match self {
+            ControlAction::SetBrightness(level) => {
+                Some(crate::brightness::BrightnessRequest::Set(*level))
+            }
+            ControlAction::BrightnessUp => Some(crate::brightness::BrightnessRequest::Up),
+            ControlAction::BrightnessDown => Some(crate::brightness::BrightnessRequest::Down),
+            _ => None,
+        }
+    }
+}
+
+/// The topic the brightness of the keypad can be set on, with the level in percent as payload
+pub(crate) fn brightness_topic(control_prefix: &str) -> String {
+    format!("{control_prefix}/brightness")
+}
+
+/// The keys that a control packet applies to, taken from the topic it was published on
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ControlTarget {
+    /// `{control_prefix}/all`
+    All,
+    /// `{control_prefix}/key/{index}`
+    Key(u8),
+    /// `{control_prefix}/row/{row}`
+    Row(u8),
+    /// `{control_prefix}/col/{column}`
+    Column(u8),
+    /// `{control_prefix}/group/{name}`
+    Group(String),
+}
+
+impl ControlTarget {
+    pub(crate) fn from_topic(control_prefix: &str, topic: &str) -> Option<Self> {
+        let target = topic.strip_prefix(control_prefix)?.strip_prefix('/')?;
+
+        let target = match target.split_once('/') {
+            None if target == "all" => ControlTarget::All,
+            Some(("key", index)) => ControlTarget::Key(index.parse().ok()?),
+            Some(("row", row)) => ControlTarget::Row(row.parse().ok()?),
+            Some(("col", column)) => ControlTarget::Column(column.parse().ok()?),
+            Some(("group", name)) => ControlTarget::Group(name.to_string()),
+            _ => return None,
+        };
+
+        Some(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use tokio::io::AsyncReadExt;
+    use tokio::io::AsyncWriteExt;
+
+    /// Serve a single HTTP request with the passed status line, returning the raw request
+    async fn serve_once(status: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !String::from_utf8_lossy(&request).contains("\r\n\r\nbody") {
+                let n = stream.read(&mut buffer).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buffer[..n]);
+            }
+
+            let response =
+                format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n");
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        (url, server)
+    }
+
+    #[tokio::test]
+    async fn test_http_request() {
+        let (url, server) = serve_once("200 OK").await;
+        let headers = BTreeMap::from([(String::from("x-token"), String::from("secret"))]);
+
+        let result =
+            super::http_request("PUT", &url, &headers, Some("body"), Duration::from_secs(5)).await;
+        assert!(result.is_ok(), "{result:?}");
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("PUT /hook HTTP/1.1\r\n"), "{request}");
+        assert!(request.contains("x-token: secret\r\n"), "{request}");
+        assert!(request.ends_with("\r\n\r\nbody"), "{request}");
+    }
+
+    #[tokio::test]
+    async fn test_http_request_error_status() {
+        let (url, server) = serve_once("404 Not Found").await;
+
+        let result = super::http_request(
+            "POST",
+            &url,
+            &BTreeMap::new(),
+            Some("body"),
+            Duration::from_secs(5),
+        )
+        .await;
+        assert!(result.is_err());
+
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_http_failure_reported() {
+        let (url, server) = serve_once("500 Internal Server Error").await;
+        let config_str = format!(
+            r#"
+            mqtt_broker_addr = "localhost"
+            mqtt_broker_port = 1883
+            mqtt_subscribe_prefix = "keypad"
+            mqtt_control_prefix = "keypad/control"
+            interval_duration = "1s"
+
+            [keypad]
+            rows = 1
+            columns = 1
+            [keypad.defaults]
+
+            [keypad.pad_0_0]
+            [[keypad.pad_0_0.on_press]]
+            [keypad.pad_0_0.on_press.Http]
+            url = "{url}"
+            body = "body"
+            "#
+        );
+        let config = crate::config::Config::parse(&config_str).unwrap();
+        let broker = crate::mqtt::test_broker::TestBroker::bind().await;
+        let (_connection, client) = broker.connect().await;
+        let (action_errors, mut failed_actions) = tokio::sync::mpsc::unbounded_channel();
+        let mut state = crate::keypad::KeypadState::from_config(&config, &action_errors);
+
+        state.pressed(0, &client).await;
+        server.await.unwrap();
+
+        let error = tokio::time::timeout(Duration::from_secs(5), failed_actions.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(format!("{error:?}").contains("500"), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn test_exec_output() {
+        let args = [String::from("-c"), String::from("echo $GREETING")];
+        let env = BTreeMap::from([(String::from("GREETING"), String::from("hi"))]);
+        let child = super::spawn_command("sh", &args, &env).unwrap();
+
+        let output = super::wait_for_command("sh", child, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"hi\n");
+    }
+
+    #[tokio::test]
+    async fn test_exec_timeout() {
+        let marker = std::env::temp_dir().join(format!("keypad-test-exec-{}", std::process::id()));
+        let script = format!("sleep 1 && touch {}", marker.display());
+        let args = [String::from("-c"), script];
+        let child = super::spawn_command("sh", &args, &BTreeMap::new()).unwrap();
+
+        let started = tokio::time::Instant::now();
+        let result = super::wait_for_command("sh", child, Duration::from_millis(100)).await;
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // Killed, so it never gets to create the marker
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn test_control_target_from_topic() {
+        use super::ControlTarget;
+
+        let target = |topic| ControlTarget::from_topic("keypad/control", topic);
+        assert_eq!(target("keypad/control/all"), Some(ControlTarget::All));
+        assert_eq!(
+            target("keypad/control/key/24"),
+            Some(ControlTarget::Key(24))
+        );
+        assert_eq!(target("keypad/control/row/0"), Some(ControlTarget::Row(0)));
+        assert_eq!(
+            target("keypad/control/col/4"),
+            Some(ControlTarget::Column(4))
+        );
+        assert_eq!(
+            target("keypad/control/group/lights"),
+            Some(ControlTarget::Group("lights".to_string()))
+        );
+        assert_eq!(target("keypad/control/key/x"), None);
+        assert_eq!(target("keypad/control/all/0"), None);
+        assert_eq!(target("keypad/controlled/all"), None);
+    }
+
+    #[test]
+    fn test_control_packet_palette() {
+        let palette = BTreeMap::from([(String::from("accent"), [1, 2, 3].into())]);
+        let payload = br#"{"actions": [{"SetColor": {"released": "accent", "pressed": "red"}}]}"#;
+
+        let packet = super::ControlPacket::parse(payload, &palette).unwrap();
+        let Some(super::ControlAction::SetColor {
+            released, pressed, ..
+        }) = packet.actions.first()
+        else {
+            panic!("Expected SetColor, got {:?}", packet.actions);
+        };
+        assert_eq!(released.map(|color| color.as_slice()), Some([1, 2, 3]));
+        assert_eq!(pressed.map(|color| color.as_slice()), Some([255, 0, 0]));
+
+        assert!(super::ControlPacket::parse(payload, &BTreeMap::new()).is_err());
+    }
 }
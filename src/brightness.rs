@@ -0,0 +1,155 @@
+use chrono::NaiveTime;
+
+use crate::util::Rgb;
+
+/// The brightness of the keypad in percent, applied to all colors when frames are built
+#[derive(Clone, Debug)]
+pub struct Brightness {
+    level: u8,
+    configured_level: u8,
+    step: u8,
+    gamma: f32,
+    night: Option<NightMode>,
+}
+
+/// Limits the brightness between two times of the day
+#[derive(Clone, Debug)]
+struct NightMode {
+    start: NaiveTime,
+    end: NaiveTime,
+    level: u8,
+}
+
+/// A change of the brightness, requested by an action
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BrightnessRequest {
+    Set(u8),
+    Up,
+    Down,
+}
+
+impl From<&crate::config::BrightnessConfig> for Brightness {
+    fn from(config: &crate::config::BrightnessConfig) -> Self {
+        Self {
+            level: config.level,
+            configured_level: config.level,
+            step: config.step,
+            gamma: config.gamma,
+            night: config.night.as_ref().map(|night| NightMode {
+                start: night.start,
+                end: night.end,
+                level: night.level,
+            }),
+        }
+    }
+}
+
+impl Brightness {
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    /// Keep the level of the previous brightness, if it was changed at runtime
+    pub fn adopt_runtime_state(&mut self, previous: &Brightness) {
+        if previous.level != previous.configured_level {
+            self.level = previous.level;
+        }
+    }
+
+    pub fn apply_request(&mut self, request: BrightnessRequest) {
+        self.level = match request {
+            BrightnessRequest::Set(level) => level.min(100),
+            BrightnessRequest::Up => self.level.saturating_add(self.step).min(100),
+            BrightnessRequest::Down => self.level.saturating_sub(self.step),
+        };
+        tracing::debug!(level = self.level, "Set brightness");
+    }
+
+    /// The brightness at the time of the day, limited by night mode
+    pub fn effective_level(&self, time: NaiveTime) -> u8 {
+        match self.night.as_ref() {
+            Some(night) if night.is_active(time) => self.level.min(night.level),
+            _ => self.level,
+        }
+    }
+
+    /// Dim the color to the brightness at the time of the day and correct it with the gamma
+    pub fn apply(&self, color: Rgb, time: NaiveTime) -> Rgb {
+        let level = f32::from(self.effective_level(time)) / 100.0;
+        color.scale(level).gamma(self.gamma)
+    }
+}
+
+impl NightMode {
+    fn is_active(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            // The night mode spans midnight
+            self.start <= time || time < self.end
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveTime;
+
+    use super::Brightness;
+    use super::BrightnessRequest;
+    use crate::util::Rgb;
+
+    fn brightness(config: &str) -> Brightness {
+        let config: crate::config::BrightnessConfig = toml::from_str(config).unwrap();
+        Brightness::from(&config)
+    }
+
+    fn time(time: &str) -> NaiveTime {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn test_brightness_requests() {
+        let mut brightness = brightness("level = 50");
+
+        brightness.apply_request(BrightnessRequest::Up);
+        assert_eq!(brightness.level(), 60);
+        brightness.apply_request(BrightnessRequest::Set(250));
+        assert_eq!(brightness.level(), 100);
+        brightness.apply_request(BrightnessRequest::Up);
+        assert_eq!(brightness.level(), 100);
+        brightness.apply_request(BrightnessRequest::Set(5));
+        brightness.apply_request(BrightnessRequest::Down);
+        assert_eq!(brightness.level(), 0);
+
+        let color = Rgb::from([200, 100, 0]);
+        brightness.apply_request(BrightnessRequest::Set(50));
+        assert_eq!(
+            brightness.apply(color, time("12:00")),
+            Rgb::from([100, 50, 0])
+        );
+    }
+
+    #[test]
+    fn test_night_mode() {
+        let brightness = brightness(
+            r#"
+            level = 80
+            gamma = 2.0
+            night = { start = "22:00", end = "07:00", level = 10 }
+            "#,
+        );
+
+        assert_eq!(brightness.effective_level(time("21:59")), 80);
+        assert_eq!(brightness.effective_level(time("22:00")), 10);
+        assert_eq!(brightness.effective_level(time("03:00")), 10);
+        assert_eq!(brightness.effective_level(time("07:00")), 80);
+
+        // Gamma correction darkens dim colors more than bright ones
+        let color = Rgb::from([255, 128, 0]);
+        assert_eq!(
+            brightness.apply(color, time("12:00")),
+            Rgb::from([163, 41, 0])
+        );
+    }
+}
@@ -0,0 +1,638 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use miette::IntoDiagnostic;
+use miette::WrapErr;
+use serde::Deserialize;
+use serde::de::IntoDeserializer;
+use toml::Spanned;
+use toml::de::DeTable;
+use toml::de::DeValue;
+
+/// All problems found in a configuration file
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+#[error("Found {} problem(s) in {path}", problems.len())]
+pub struct CheckError {
+    path: String,
+
+    #[source_code]
+    source_code: miette::NamedSource<String>,
+
+    #[related]
+    problems: Vec<Problem>,
+}
+
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+pub enum Problem {
+    #[error("{message}")]
+    Toml {
+        message: String,
+        #[label]
+        span: Option<miette::SourceSpan>,
+    },
+
+    #[error("Invalid action: {message}")]
+    InvalidAction {
+        message: String,
+        #[label]
+        span: miette::SourceSpan,
+    },
+
+    #[error("Key index {index} is out of range for the keypad with {key_count} keys")]
+    KeyOutOfRange {
+        index: u8,
+        key_count: u8,
+        #[label]
+        span: Option<miette::SourceSpan>,
+    },
+
+    #[error("Label '{label}' is used by more than one pad on page '{page}'")]
+    DuplicateLabel {
+        label: String,
+        page: String,
+        #[label("used again here")]
+        span: Option<miette::SourceSpan>,
+    },
+
+    #[error("Publish topic '{topic}' contains a wildcard")]
+    #[diagnostic(help("'+' and '#' can only be used to subscribe, not to publish"))]
+    WildcardTopic {
+        topic: String,
+        #[label]
+        span: miette::SourceSpan,
+    },
+
+    #[error("Page '{page}' cannot be reached")]
+    #[diagnostic(help("Switch to it with a SwitchPage or PushPage action of a key or chord"))]
+    UnreachablePage {
+        page: String,
+        #[label]
+        span: Option<miette::SourceSpan>,
+    },
+
+    /// A problem found when loading the configuration normally
+    #[error("{message}")]
+    Config {
+        message: String,
+        #[help]
+        help: Option<String>,
+        #[label]
+        span: Option<miette::SourceSpan>,
+    },
+}
+
+impl Problem {
+    fn toml(error: &toml::de::Error) -> Self {
+        Problem::Toml {
+            message: error.message().trim().to_string(),
+            span: error.span().map(miette::SourceSpan::from),
+        }
+    }
+
+    fn config(error: crate::config::ConfigError, document: &DeTable<'_>) -> Self {
+        use crate::config::ConfigError;
+
+        let path = match &error {
+            ConfigError::InvalidGrid { .. } => vec![Segment::Key("keypad")],
+            ConfigError::MissingPad { page, .. } => keymap_path(page),
+            ConfigError::InvalidPressCount { page, pad, .. } => {
+                let mut path = keymap_path(page);
+                path.extend([Segment::Key(pad), Segment::Key("on_multi_press")]);
+                path
+            }
+            ConfigError::InvalidChord { chord, .. } => {
+                let keys = Segment::Key("keys");
+                vec![Segment::Key("chords"), Segment::Index(*chord), keys]
+            }
+            ConfigError::UnknownPad { page, pad } => {
+                let mut path = keymap_path(page);
+                path.push(Segment::Key(pad));
+                path
+            }
+            ConfigError::ReservedPageName(page) => vec![Segment::Key("pages"), Segment::Key(page)],
+            ConfigError::InvalidGroupName(name) => vec![Segment::Key("groups"), Segment::Key(name)],
+            ConfigError::TemplateCycle(cycle) => {
+                vec![Segment::Key("templates"), Segment::Key(&cycle[0])]
+            }
+            ConfigError::InvalidFrameRate => vec![Segment::Key("animation")],
+            ConfigError::InvalidBrightness(_) | ConfigError::InvalidGamma(_) => {
+                vec![Segment::Key("brightness")]
+            }
+            ConfigError::InvalidNodeId(_) | ConfigError::HomeAssistantWithoutEvents => {
+                vec![Segment::Key("homeassistant")]
+            }
+            ConfigError::IncompleteClientCertificate => vec![Segment::Key("mqtt_tls")],
+            // The only password, or the second one of the ambiguous passwords
+            ConfigError::PasswordWithoutUsername | ConfigError::AmbiguousPassword => {
+                let skip = usize::from(matches!(error, ConfigError::AmbiguousPassword));
+                ["mqtt_password", "mqtt_password_file", "mqtt_password_env"]
+                    .into_iter()
+                    .filter(|key| document.get(*key).is_some())
+                    .nth(skip)
+                    .map(Segment::Key)
+                    .into_iter()
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+
+        Problem::Config {
+            message: error.to_string(),
+            help: miette::Diagnostic::help(&error).map(|help| help.to_string()),
+            span: find_span(document, &path),
+        }
+    }
+}
+
+/// Check the configuration file, returning all problems at once with their location in the file
+pub async fn check_config(path: &camino::Utf8Path) -> miette::Result<()> {
+    let contents = tokio::fs::read_to_string(path)
+        .await
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to read {path}"))?;
+
+    let problems = problems(&contents);
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    Err(CheckError {
+        path: path.to_string(),
+        source_code: miette::NamedSource::new(path, contents),
+        problems,
+    }
+    .into())
+}
+
+fn problems(contents: &str) -> Vec<Problem> {
+    let document = match DeTable::parse(contents) {
+        Ok(document) => document,
+        Err(error) => return vec![Problem::toml(&error)],
+    };
+    let document = document.get_ref();
+
+    let mut problems = Vec::new();
+    let palette = crate::config::Config::parse_palette(contents).unwrap_or_default();
+    crate::util::with_palette(palette, || check_actions(document, &mut problems));
+
+    let config = match crate::config::Config::parse(contents) {
+        Ok(config) => config,
+        Err(crate::config::ConfigError::Toml(error)) => {
+            // Invalid actions are already reported, deserializing stops at the first one and
+            // loses its location in flattened keymaps
+            let message = error.message().trim();
+            let is_reported = problems.iter().any(|problem| match problem {
+                Problem::InvalidAction {
+                    message: reported, ..
+                } => reported == message,
+                _ => false,
+            });
+            if !is_reported {
+                problems.push(Problem::toml(&error));
+            }
+            return problems;
+        }
+        Err(error) => {
+            problems.push(Problem::config(error, document));
+            return problems;
+        }
+    };
+
+    check_key_indices(&config, document, &mut problems);
+    let reported_indices = problems
+        .iter()
+        .filter_map(|problem| match problem {
+            Problem::KeyOutOfRange { index, .. } => Some(*index),
+            _ => None,
+        })
+        .collect::<BTreeSet<_>>();
+
+    let errors = config.validate().err().unwrap_or_default();
+    for error in errors {
+        match error {
+            // Already reported with the location of every use
+            crate::config::ConfigError::KeyOutOfRange(index)
+                if reported_indices.contains(&index) => {}
+            error => problems.push(Problem::config(error, document)),
+        }
+    }
+
+    check_labels(&config, document, &mut problems);
+    check_reachable_pages(&config, document, &mut problems);
+    problems
+}
+
+/// Deserialize every action on its own, so that all invalid actions are found instead of only the
+/// first one
+fn check_actions(document: &DeTable<'_>, problems: &mut Vec<Problem>) {
+    for value in action_values(document) {
+        match crate::config::ActionConfig::deserialize(value.clone().into_deserializer()) {
+            Ok(crate::config::ActionConfig::Publish { topic, .. })
+                if topic.contains(['+', '#']) =>
+            {
+                problems.push(Problem::WildcardTopic {
+                    topic,
+                    span: value.span().into(),
+                });
+            }
+            Ok(_) => {}
+            Err(error) => {
+                problems.push(Problem::InvalidAction {
+                    message: error.message().trim().to_string(),
+                    span: error.span().unwrap_or_else(|| value.span()).into(),
+                });
+            }
+        }
+    }
+}
+
+/// All actions of pads, templates, defaults and chords
+fn action_values<'a, 'i>(document: &'a DeTable<'i>) -> Vec<&'a Spanned<DeValue<'i>>> {
+    let keypad = get_table(document, "keypad");
+    let templates = get_table(document, "templates");
+    let pages = get_table(document, "pages");
+
+    // The keypad table contains the default page and the defaults next to the grid size
+    let pads = keypad
+        .into_iter()
+        .flat_map(tables)
+        .chain(templates.into_iter().flat_map(tables))
+        .chain(pages.into_iter().flat_map(tables).flat_map(tables));
+
+    let pad_actions = pads.flat_map(|pad| {
+        let multi_press_actions = get_array(pad, "on_multi_press")
+            .iter()
+            .filter_map(as_table)
+            .flat_map(|multi_press| get_array(multi_press, "actions"));
+
+        ["on_press", "on_release", "on_long_press", "on_double_press"]
+            .into_iter()
+            .flat_map(|key| get_array(pad, key))
+            .chain(multi_press_actions)
+    });
+    let chord_actions = get_array(document, "chords")
+        .iter()
+        .filter_map(as_table)
+        .flat_map(|chord| get_array(chord, "actions"));
+
+    pad_actions.chain(chord_actions).collect()
+}
+
+fn as_table<'a, 'i>(value: &'a Spanned<DeValue<'i>>) -> Option<&'a DeTable<'i>> {
+    match value.get_ref() {
+        DeValue::Table(table) => Some(table),
+        _ => None,
+    }
+}
+
+fn tables<'a, 'i>(table: &'a DeTable<'i>) -> impl Iterator<Item = &'a DeTable<'i>> {
+    table.values().filter_map(as_table)
+}
+
+fn get_table<'a, 'i>(table: &'a DeTable<'i>, key: &str) -> Option<&'a DeTable<'i>> {
+    table.get(key).and_then(as_table)
+}
+
+fn get_array<'a, 'i>(table: &'a DeTable<'i>, key: &str) -> &'a [Spanned<DeValue<'i>>] {
+    match table.get(key).map(Spanned::get_ref) {
+        Some(DeValue::Array(array)) => array,
+        _ => &[],
+    }
+}
+
+/// A step on the path to a value in the configuration file
+#[derive(Clone, Copy)]
+enum Segment<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+/// The location of the key or array element at the end of the path
+fn find_span(document: &DeTable<'_>, path: &[Segment<'_>]) -> Option<miette::SourceSpan> {
+    let mut value: Option<&DeValue<'_>> = None;
+    let mut span = None;
+    for segment in path {
+        let (next, next_span) = match (segment, value) {
+            (Segment::Key(key), None) => {
+                let (key, next) = document.get_key_value(*key)?;
+                (next, key.span())
+            }
+            (Segment::Key(key), Some(DeValue::Table(table))) => {
+                let (key, next) = table.get_key_value(*key)?;
+                (next, key.span())
+            }
+            (Segment::Index(index), Some(DeValue::Array(array))) => {
+                let next = array.get(*index)?;
+                (next, next.span())
+            }
+            _ => return None,
+        };
+        value = Some(next.get_ref());
+        span = Some(next_span);
+    }
+
+    span.map(miette::SourceSpan::from)
+}
+
+/// The path of the keymap of a page in the configuration file
+fn keymap_path(page: &str) -> Vec<Segment<'_>> {
+    match page {
+        crate::konst::DEFAULT_PAGE => vec![Segment::Key("keypad")],
+        page => vec![Segment::Key("pages"), Segment::Key(page)],
+    }
+}
+
+/// Key indices of chords and groups have to exist on the keypad
+fn check_key_indices(
+    config: &crate::config::Config,
+    document: &DeTable<'_>,
+    problems: &mut Vec<Problem>,
+) {
+    // An invalid grid is already reported
+    let Ok(key_count) =
+        u8::try_from(u16::from(config.keypad.rows) * u16::from(config.keypad.columns))
+    else {
+        return;
+    };
+
+    let mut out_of_range = |index: u8, path: &[Segment<'_>]| {
+        if index >= key_count {
+            problems.push(Problem::KeyOutOfRange {
+                index,
+                key_count,
+                span: find_span(document, path),
+            });
+        }
+    };
+
+    for (i, chord) in config.chords.iter().enumerate() {
+        for (j, index) in chord.keys.iter().enumerate() {
+            let path = [
+                Segment::Key("chords"),
+                Segment::Index(i),
+                Segment::Key("keys"),
+                Segment::Index(j),
+            ];
+            out_of_range(*index, &path);
+        }
+    }
+
+    for (name, keys) in config.groups.iter() {
+        for (j, index) in keys.iter().enumerate() {
+            let path = [
+                Segment::Key("groups"),
+                Segment::Key(name),
+                Segment::Index(j),
+            ];
+            out_of_range(*index, &path);
+        }
+    }
+}
+
+/// Labels have to identify the pads of a page
+fn check_labels(
+    config: &crate::config::Config,
+    document: &DeTable<'_>,
+    problems: &mut Vec<Problem>,
+) {
+    let keymaps = std::iter::once((crate::konst::DEFAULT_PAGE, &config.keypad.pads)).chain(
+        config
+            .pages
+            .iter()
+            .map(|(name, keymap)| (name.as_str(), keymap)),
+    );
+
+    for (page, keymap) in keymaps {
+        let pads = keymap.0.iter().collect::<BTreeMap<_, _>>();
+        let mut labels = BTreeSet::new();
+        for (name, pad) in pads {
+            let Some(label) = pad.label.as_deref() else {
+                continue;
+            };
+            if labels.insert(label) {
+                continue;
+            }
+
+            // The label may be inherited from a template, then the pad itself is marked
+            let mut path = keymap_path(page);
+            path.push(Segment::Key(name));
+            let pad_span = find_span(document, &path);
+            path.push(Segment::Key("label"));
+            let span = find_span(document, &path).or(pad_span);
+            problems.push(Problem::DuplicateLabel {
+                label: label.to_string(),
+                page: page.to_string(),
+                span,
+            });
+        }
+    }
+}
+
+/// Every page has to be reachable from the default page with the actions of keys and chords
+fn check_reachable_pages(
+    config: &crate::config::Config,
+    document: &DeTable<'_>,
+    problems: &mut Vec<Problem>,
+) {
+    fn page_targets<'a>(
+        actions: impl IntoIterator<Item = &'a crate::config::ActionConfig>,
+    ) -> impl Iterator<Item = &'a str> {
+        actions.into_iter().filter_map(|action| match action {
+            crate::config::ActionConfig::SwitchPage { name }
+            | crate::config::ActionConfig::PushPage { name } => Some(name.as_str()),
+            _ => None,
+        })
+    }
+
+    // Chords work on all pages
+    let mut reachable = std::iter::once(crate::konst::DEFAULT_PAGE)
+        .chain(page_targets(
+            config.chords.iter().flat_map(|chord| &chord.actions),
+        ))
+        .collect::<BTreeSet<_>>();
+    let mut pending = reachable.iter().copied().collect::<Vec<_>>();
+
+    while let Some(page) = pending.pop() {
+        let keymap = match page {
+            crate::konst::DEFAULT_PAGE => &config.keypad.pads,
+            page => match config.pages.get(page) {
+                Some(keymap) => keymap,
+                None => continue,
+            },
+        };
+
+        let actions = keymap.0.values().flat_map(|pad| {
+            let multi_press_actions = pad.on_multi_press.iter().flatten().flat_map(|m| &m.actions);
+            [
+                &pad.on_press,
+                &pad.on_release,
+                &pad.on_long_press,
+                &pad.on_double_press,
+            ]
+            .into_iter()
+            .flatten()
+            .flatten()
+            .chain(multi_press_actions)
+        });
+        for target in page_targets(actions) {
+            if reachable.insert(target) {
+                pending.push(target);
+            }
+        }
+    }
+
+    for page in config.pages.keys() {
+        if !reachable.contains(page.as_str()) {
+            problems.push(Problem::UnreachablePage {
+                page: page.to_string(),
+                span: find_span(document, &[Segment::Key("pages"), Segment::Key(page)]),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::problems;
+
+    const HEADER: &str = r#"
+        mqtt_broker_addr = "localhost"
+        mqtt_broker_port = 1883
+        mqtt_subscribe_prefix = "keypad"
+        mqtt_control_prefix = "keypad/control"
+        interval_duration = "1s"
+    "#;
+
+    /// The messages of all problems with the text they point to
+    fn located_problems(config_str: &str) -> Vec<(String, &str)> {
+        problems(config_str)
+            .iter()
+            .map(|problem| {
+                let label = miette::Diagnostic::labels(problem)
+                    .and_then(|mut labels| labels.next())
+                    .unwrap();
+                let span = label.offset()..label.offset() + label.len();
+                (problem.to_string(), &config_str[span])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_invalid_actions() {
+        let config_str = format!(
+            r#"{HEADER}
+            [keypad]
+            rows = 1
+            columns = 2
+            pad_0_0 = {{ on_press = ["Blink", {{ Publish = {{ topic = "lights/+/set", payload = "on" }} }}] }}
+            pad_0_1 = {{ on_release = ["Toggle"] }}
+            "#
+        );
+
+        let problems = located_problems(&config_str);
+        assert_eq!(problems.len(), 3);
+        assert!(
+            problems[0]
+                .0
+                .starts_with("Invalid action: unknown variant `Blink`")
+        );
+        assert_eq!(problems[0].1, r#""Blink""#);
+        assert_eq!(
+            problems[1].0,
+            "Publish topic 'lights/+/set' contains a wildcard"
+        );
+        assert!(
+            problems[2]
+                .0
+                .starts_with("Invalid action: unknown variant `Toggle`")
+        );
+    }
+
+    #[test]
+    fn test_semantic_problems() {
+        let config_str = format!(
+            r#"{HEADER}
+            [keypad]
+            rows = 1
+            columns = 2
+            pad_0_0 = {{ label = "Lamp", on_press = [{{ SwitchPage = {{ name = "media" }} }}] }}
+            pad_0_1 = {{ label = "Lamp" }}
+
+            [pages.media]
+            pad_0_0 = {{ on_press = ["PopPage"] }}
+            pad_0_1 = {{}}
+
+            [pages.hidden]
+            pad_0_0 = {{}}
+            pad_0_1 = {{}}
+
+            [[chords]]
+            keys = [0, 5]
+            actions = []
+
+            [groups]
+            lights = [7]
+            "#
+        );
+
+        assert_eq!(
+            located_problems(&config_str),
+            [
+                (
+                    String::from("Key index 5 is out of range for the keypad with 2 keys"),
+                    "5"
+                ),
+                (
+                    String::from("Key index 7 is out of range for the keypad with 2 keys"),
+                    "7"
+                ),
+                (
+                    String::from("Label 'Lamp' is used by more than one pad on page 'default'"),
+                    "label"
+                ),
+                (String::from("Page 'hidden' cannot be reached"), "hidden"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_all_validation_problems() {
+        let config_str = format!(
+            r#"{HEADER}
+            mqtt_password = "secret"
+            mqtt_password_env = "KEYPAD_MQTT_PASSWORD"
+
+            [keypad]
+            rows = 1
+            columns = 2
+            pad_0_0 = {{ on_multi_press = [{{ count = 1, actions = [] }}] }}
+            pad_0_1 = {{}}
+
+            [[chords]]
+            keys = [1]
+            actions = []
+            "#
+        );
+
+        assert_eq!(
+            located_problems(&config_str),
+            [
+                (
+                    String::from("More than one MQTT password is configured"),
+                    "mqtt_password_env"
+                ),
+                (
+                    String::from(
+                        "Multi press actions of 'pad_0_0' on page 'default' are bound to 1 \
+                         presses, must be at least 2"
+                    ),
+                    "on_multi_press"
+                ),
+                (
+                    String::from("Chord [1] must consist of at least two keys"),
+                    "keys"
+                ),
+            ]
+        );
+    }
+}
@@ -13,6 +13,15 @@ pub struct Cli {
     /// Optional file to log to
     #[clap(long = "config")]
     pub config_path: Option<camino::Utf8PathBuf>,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// Check the configuration file and report all problems, without connecting to the broker
+    CheckConfig,
 }
 
 #[derive(Default, Debug, Copy, Clone, clap::ValueEnum)]
@@ -4,27 +4,267 @@ use camino::Utf8PathBuf;
 pub struct Config {
     pub mqtt_broker_addr: String,
     pub mqtt_broker_port: u16,
-    // pub mqtt_client_id: String, // TODO Unused because cloudmqtt does not yet have the interface
+    #[serde(default)]
+    pub mqtt_client_id: Option<String>,
+
+    #[serde(default)]
+    pub mqtt_username: Option<String>,
+
+    /// The password for `mqtt_username`
+    ///
+    /// Prefer `mqtt_password_file` or `mqtt_password_env` to keep it out of the config file.
+    #[serde(default)]
+    pub mqtt_password: Option<String>,
+
+    /// A file containing the password for `mqtt_username`
+    #[serde(default)]
+    pub mqtt_password_file: Option<Utf8PathBuf>,
+
+    /// The name of an environment variable containing the password for `mqtt_username`
+    #[serde(default)]
+    pub mqtt_password_env: Option<String>,
+
+    #[serde(default, with = "humantime_serde::option")]
+    pub mqtt_keepalive: Option<std::time::Duration>,
+
+    /// Connect to the broker via TLS
+    #[serde(default)]
+    pub mqtt_tls: Option<TlsConfig>,
+
     pub mqtt_subscribe_prefix: String,
     pub mqtt_control_prefix: String,
 
+    /// Publish `online` on this topic while running and `offline` when shutting down
+    ///
+    /// Both are retained, and `offline` is also the last will for when the connection is lost.
+    #[serde(default)]
+    pub mqtt_availability_topic: Option<String>,
+
+    /// Publish human-readable events of all keys on `{mqtt_event_prefix}/events/key/{index}`
+    #[serde(default)]
+    pub mqtt_event_prefix: Option<String>,
+
+    /// Publish the state of every key on `{mqtt_state_prefix}/state/key/{index}` and of the whole
+    /// keypad on `{mqtt_state_prefix}/state/keypad`, whenever it changes
+    ///
+    /// The state is retained, so that new subscribers get it right away.
+    #[serde(default)]
+    pub mqtt_state_prefix: Option<String>,
+
     /// Set a duration for the interval
     ///
     /// Used for blinking, for example
     #[serde(with = "humantime_serde::option")]
     pub interval_duration: Option<std::time::Duration>,
 
+    /// The layout of the keypad and the keymap of its default page
     pub keypad: KeypadConfig,
+
+    /// Additional pages with their own keymaps, which can be switched to at runtime
+    #[serde(default)]
+    pub pages: std::collections::BTreeMap<String, Keymap>,
+
+    /// Named pad settings that pads can inherit from with `extends = "name"`
+    #[serde(default)]
+    pub templates: std::collections::BTreeMap<String, PadConfig>,
+
+    /// Actions that are triggered by holding down multiple keys together
+    #[serde(default)]
+    pub chords: Vec<ChordConfig>,
+
+    /// Named groups of key indices, which can be controlled together on `{control_prefix}/group/{name}`
+    #[serde(default)]
+    pub groups: std::collections::BTreeMap<String, Vec<u8>>,
+
+    /// Announce the keys to Home Assistant via MQTT discovery
+    #[serde(default)]
+    pub homeassistant: Option<HomeAssistantConfig>,
+
+    #[serde(default)]
+    pub animation: AnimationConfig,
+
+    #[serde(default)]
+    pub brightness: BrightnessConfig,
+
+    /// Named colors, kept to resolve colors in control packets
+    #[serde(default)]
+    pub palette: std::collections::BTreeMap<String, crate::util::Rgb>,
+}
+
+/// The `[palette]` section of the configuration, which has to be known before all other colors
+/// can be deserialized
+#[derive(Debug, Default, serde::Deserialize)]
+struct PaletteConfig {
+    /// Named colors that can be used instead of color values, e.g. `released = "accent"`
+    #[serde(default)]
+    palette: std::collections::BTreeMap<String, crate::util::Rgb>,
 }
 
 impl Config {
-    pub async fn load(path_overwrite: Option<camino::Utf8PathBuf>) -> Result<Self, ConfigError> {
-        let path = path_overwrite
+    /// Find the path of the configuration file, if it is not overwritten by the user
+    pub fn find_path(
+        path_overwrite: Option<camino::Utf8PathBuf>,
+    ) -> Result<Utf8PathBuf, ConfigError> {
+        path_overwrite
             .map(Ok)
-            .unwrap_or_else(Self::find_config_path_from_xdg)?;
-        let config_contents = tokio::fs::read_to_string(&path).await?;
+            .unwrap_or_else(Self::find_config_path_from_xdg)
+    }
+
+    pub async fn load(path: &camino::Utf8Path) -> Result<Self, ConfigError> {
+        let config_contents = tokio::fs::read_to_string(path).await?;
+
+        let config = Self::parse(&config_contents)?;
+        config.validate().map_err(ConfigError::Invalid)?;
+        Ok(config)
+    }
+
+    /// Parse the configuration, resolving colors that refer to the palette
+    pub(crate) fn parse(config_contents: &str) -> Result<Self, ConfigError> {
+        let palette = Self::parse_palette(config_contents)?;
+        let mut config: Self =
+            crate::util::with_palette(palette, || toml::from_str(config_contents))
+                .map_err(ConfigError::Toml)?;
+        config.apply_templates()?;
+        Ok(config)
+    }
+
+    /// Parse only the palette of the configuration
+    pub(crate) fn parse_palette(
+        config_contents: &str,
+    ) -> Result<std::collections::BTreeMap<String, crate::util::Rgb>, ConfigError> {
+        toml::from_str::<PaletteConfig>(config_contents)
+            .map(|config| config.palette)
+            .map_err(ConfigError::Toml)
+    }
+
+    /// Merge the templates and the defaults into all pads, so that they are complete
+    ///
+    /// If there are defaults, pads that are not configured at all use them.
+    fn apply_templates(&mut self) -> Result<(), ConfigError> {
+        // Templates that no pad extends still have to be valid
+        for (name, template) in self.templates.iter() {
+            let mut chain = vec![name.to_string()];
+            template
+                .clone()
+                .resolve_templates(&self.templates, &mut chain)?;
+        }
+
+        let defaults = match self.keypad.defaults.take() {
+            Some(defaults) => {
+                let (rows, columns) = (self.keypad.rows, self.keypad.columns);
+                std::iter::once(&mut self.keypad.pads)
+                    .chain(self.pages.values_mut())
+                    .for_each(|keymap| keymap.insert_missing_pads(rows, columns));
+                defaults.resolve_templates(&self.templates, &mut Vec::new())?
+            }
+            None => PadConfig::default(),
+        };
+
+        let keymaps = std::iter::once(&mut self.keypad.pads).chain(self.pages.values_mut());
+        for pad in keymaps.flat_map(|keymap| keymap.0.values_mut()) {
+            let resolved =
+                std::mem::take(pad).resolve_templates(&self.templates, &mut Vec::new())?;
+            *pad = resolved.merge(&defaults);
+        }
+
+        Ok(())
+    }
+
+    /// Check the configuration, returning all problems instead of only the first one
+    pub(crate) fn validate(&self) -> Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+
+        let passwords = [
+            self.mqtt_password.is_some(),
+            self.mqtt_password_file.is_some(),
+            self.mqtt_password_env.is_some(),
+        ];
+        match passwords.into_iter().filter(|is_set| *is_set).count() {
+            0 => {}
+            1 if self.mqtt_username.is_some() => {}
+            1 => errors.push(ConfigError::PasswordWithoutUsername),
+            _ => errors.push(ConfigError::AmbiguousPassword),
+        }
+
+        let has_incomplete_client_certificate = |tls: &TlsConfig| {
+            tls.client_certificate_file.is_some() != tls.client_key_file.is_some()
+        };
+        if self
+            .mqtt_tls
+            .as_ref()
+            .is_some_and(has_incomplete_client_certificate)
+        {
+            errors.push(ConfigError::IncompleteClientCertificate);
+        }
+
+        if self.animation.frame_rate == 0 {
+            errors.push(ConfigError::InvalidFrameRate);
+        }
+
+        self.brightness.validate(&mut errors);
 
-        toml::from_str(&config_contents).map_err(ConfigError::Toml)
+        // Keymaps and key indices can only be checked against a valid grid
+        if self.keypad.validate(&mut errors) {
+            for (name, keymap) in self.pages.iter() {
+                keymap.validate(name, self.keypad.rows, self.keypad.columns, &mut errors);
+            }
+
+            let chord_keys = self.chords.iter().flat_map(|chord| chord.keys.iter());
+            let group_keys = self.groups.values().flatten();
+            errors.extend(
+                chord_keys
+                    .chain(group_keys)
+                    .filter(|index| **index >= self.keypad.key_count())
+                    .map(|index| ConfigError::KeyOutOfRange(*index)),
+            );
+        }
+
+        errors.extend(
+            self.pages
+                .keys()
+                .filter(|name| *name == crate::konst::DEFAULT_PAGE)
+                .map(|name| ConfigError::ReservedPageName(name.to_string())),
+        );
+
+        for (chord, config) in self.chords.iter().enumerate() {
+            if config.keys.len() < 2 {
+                errors.push(ConfigError::InvalidChord {
+                    chord,
+                    keys: config.keys.clone(),
+                });
+            }
+        }
+
+        if let Some(homeassistant) = self.homeassistant.as_ref() {
+            homeassistant.validate(&mut errors);
+
+            if self.mqtt_event_prefix.is_none() {
+                errors.push(ConfigError::HomeAssistantWithoutEvents);
+            }
+        }
+
+        errors.extend(
+            self.groups
+                .keys()
+                .filter(|name| name.is_empty() || name.contains(['/', '+', '#']))
+                .map(|name| ConfigError::InvalidGroupName(name.to_string())),
+        );
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// All topics that pads on any page take their state from
+    pub fn state_topics(&self) -> std::collections::BTreeSet<&str> {
+        std::iter::once(&self.keypad.pads)
+            .chain(self.pages.values())
+            .flat_map(|keymap| keymap.0.values())
+            .filter_map(|pad| pad.state_topic.as_ref())
+            .map(|state_topic| state_topic.topic.as_str())
+            .collect()
     }
 
     fn find_config_path_from_xdg() -> Result<Utf8PathBuf, ConfigError> {
@@ -35,7 +275,7 @@ impl Config {
     }
 }
 
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
 pub enum ConfigError {
     #[error(transparent)]
     Io(#[from] std::io::Error),
@@ -48,60 +288,627 @@ pub enum ConfigError {
 
     #[error("toml error")]
     Toml(#[source] toml::de::Error),
+
+    #[error("Found {} problem(s) in the configuration", .0.len())]
+    Invalid(#[related] Vec<ConfigError>),
+
+    #[error(
+        "Invalid keypad grid: {rows} rows x {columns} columns, must have between 1 and 255 keys"
+    )]
+    InvalidGrid { rows: u8, columns: u8 },
+
+    #[error("Missing configuration for pad in row {row}, column {column} on page '{page}'")]
+    MissingPad { page: String, row: u8, column: u8 },
+
+    #[error("Pad configuration '{pad}' on page '{page}' does not match any key on the keypad")]
+    UnknownPad { page: String, pad: String },
+
+    #[error("Page name '{0}' is reserved for the keymap in the [keypad] section")]
+    ReservedPageName(String),
+
+    #[error("Template '{0}' does not exist")]
+    #[diagnostic(help("Define it in a [templates.{0}] section"))]
+    UnknownTemplate(String),
+
+    /// The names of the templates in the cycle, starting and ending with the same template
+    #[error("Templates extend each other in a cycle: {}", .0.join(" -> "))]
+    TemplateCycle(Vec<String>),
+
+    #[error(
+        "Multi press actions of '{pad}' on page '{page}' are bound to {count} presses, must be at least 2"
+    )]
+    InvalidPressCount {
+        page: String,
+        pad: String,
+        count: u8,
+    },
+
+    /// The index of the chord in the `chords` array and its keys
+    #[error("Chord {keys:?} must consist of at least two keys")]
+    InvalidChord { chord: usize, keys: Vec<u8> },
+
+    #[error("Key index {0} is out of range for the keypad")]
+    KeyOutOfRange(u8),
+
+    #[error("Group name '{0}' cannot be used in an MQTT topic")]
+    InvalidGroupName(String),
+
+    #[error("An MQTT password is configured, but no username")]
+    #[diagnostic(help("Set mqtt_username"))]
+    PasswordWithoutUsername,
+
+    #[error("More than one MQTT password is configured")]
+    #[diagnostic(help("Only set one of mqtt_password, mqtt_password_file and mqtt_password_env"))]
+    AmbiguousPassword,
+
+    #[error("TLS client certificate and key have to be configured together")]
+    #[diagnostic(help("Set both client_certificate_file and client_key_file in mqtt_tls"))]
+    IncompleteClientCertificate,
+
+    #[error("The animation frame rate must be at least 1")]
+    InvalidFrameRate,
+
+    #[error("Brightness {0} is out of range, must be between 0 and 100 percent")]
+    InvalidBrightness(u8),
+
+    #[error("Gamma {0} is invalid, must be greater than 0")]
+    InvalidGamma(f32),
+
+    #[error("Home Assistant node ID '{0}' must only consist of letters, digits, '_' and '-'")]
+    InvalidNodeId(String),
+
+    #[error("Home Assistant discovery requires key events")]
+    #[diagnostic(help("Set mqtt_event_prefix"))]
+    HomeAssistantWithoutEvents,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct AnimationConfig {
+    /// How many frames per second are published while an effect is running
+    #[serde(default = "default_frame_rate")]
+    pub frame_rate: u32,
+}
+
+impl Default for AnimationConfig {
+    fn default() -> Self {
+        Self {
+            frame_rate: default_frame_rate(),
+        }
+    }
+}
+
+fn default_frame_rate() -> u32 {
+    20
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct BrightnessConfig {
+    /// The brightness of all colors in percent
+    #[serde(default = "default_brightness_level")]
+    pub level: u8,
+
+    /// How many percent `BrightnessUp` and `BrightnessDown` change the brightness
+    #[serde(default = "default_brightness_step")]
+    pub step: u8,
+
+    /// The gamma curve applied to all colors, 1.0 leaves them unchanged
+    #[serde(default = "default_gamma")]
+    pub gamma: f32,
+
+    /// Limit the brightness during the night
+    #[serde(default)]
+    pub night: Option<NightModeConfig>,
+}
+
+impl Default for BrightnessConfig {
+    fn default() -> Self {
+        Self {
+            level: default_brightness_level(),
+            step: default_brightness_step(),
+            gamma: default_gamma(),
+            night: None,
+        }
+    }
+}
+
+impl BrightnessConfig {
+    fn validate(&self, errors: &mut Vec<ConfigError>) {
+        let levels = std::iter::once(self.level)
+            .chain(std::iter::once(self.step))
+            .chain(self.night.as_ref().map(|night| night.level));
+        errors.extend(
+            levels
+                .filter(|level| *level > 100)
+                .map(ConfigError::InvalidBrightness),
+        );
+
+        if !self.gamma.is_finite() || self.gamma <= 0.0 {
+            errors.push(ConfigError::InvalidGamma(self.gamma));
+        }
+    }
+}
+
+fn default_brightness_level() -> u8 {
+    100
+}
+
+fn default_brightness_step() -> u8 {
+    10
+}
+
+fn default_gamma() -> f32 {
+    1.0
+}
+
+/// Limits the brightness from `start` until `end`, in local time
+#[derive(Debug, serde::Deserialize)]
+pub struct NightModeConfig {
+    pub start: chrono::NaiveTime,
+    pub end: chrono::NaiveTime,
+    /// The highest brightness in percent during the night
+    pub level: u8,
+}
+
+#[derive(Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct TlsConfig {
+    /// CA certificates to verify the broker with, instead of the Mozilla root certificates
+    #[serde(default)]
+    pub ca_file: Option<Utf8PathBuf>,
+
+    /// Certificate to authenticate the client with
+    #[serde(default)]
+    pub client_certificate_file: Option<Utf8PathBuf>,
+
+    /// Private key of the client certificate
+    #[serde(default)]
+    pub client_key_file: Option<Utf8PathBuf>,
+
+    /// Accept any certificate of the broker, only for testing
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct HomeAssistantConfig {
+    /// The topic prefix Home Assistant discovers devices on
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+
+    /// The ID of the keypad device in Home Assistant, also used for the IDs of its entities
+    #[serde(default = "default_node_id")]
+    pub node_id: String,
+
+    /// The name of the keypad device in Home Assistant, defaults to the node ID
+    #[serde(default)]
+    pub device_name: Option<String>,
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+fn default_node_id() -> String {
+    env!("CARGO_PKG_NAME").to_string()
+}
+
+impl HomeAssistantConfig {
+    fn validate(&self, errors: &mut Vec<ConfigError>) {
+        let is_valid_char = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+        if self.node_id.is_empty() || !self.node_id.chars().all(is_valid_char) {
+            errors.push(ConfigError::InvalidNodeId(self.node_id.to_string()));
+        }
+    }
 }
 
 #[derive(Debug, serde::Deserialize)]
 pub struct KeypadConfig {
-    pub pad_0_0: PadConfig,
-    pub pad_0_1: PadConfig,
-    pub pad_0_2: PadConfig,
-    pub pad_0_3: PadConfig,
-    pub pad_0_4: PadConfig,
-    pub pad_1_0: PadConfig,
-    pub pad_1_1: PadConfig,
-    pub pad_1_2: PadConfig,
-    pub pad_1_3: PadConfig,
-    pub pad_1_4: PadConfig,
-    pub pad_2_0: PadConfig,
-    pub pad_2_1: PadConfig,
-    pub pad_2_2: PadConfig,
-    pub pad_2_3: PadConfig,
-    pub pad_2_4: PadConfig,
-    pub pad_3_0: PadConfig,
-    pub pad_3_1: PadConfig,
-    pub pad_3_2: PadConfig,
-    pub pad_3_3: PadConfig,
-    pub pad_3_4: PadConfig,
-    pub pad_4_0: PadConfig,
-    pub pad_4_1: PadConfig,
-    pub pad_4_2: PadConfig,
-    pub pad_4_3: PadConfig,
-    pub pad_4_4: PadConfig,
+    /// Number of rows of the keypad grid
+    #[serde(default = "default_grid_size")]
+    pub rows: u8,
+
+    /// Number of columns of the keypad grid
+    #[serde(default = "default_grid_size")]
+    pub columns: u8,
+
+    /// Settings of all pads on all pages, used for all fields a pad and its template do not set
+    ///
+    /// If set, pads can be left out of keymaps and only use these settings.
+    #[serde(default)]
+    pub defaults: Option<PadConfig>,
+
+    /// The keymap of the default page
+    #[serde(flatten)]
+    pub pads: Keymap,
+}
+
+fn default_grid_size() -> u8 {
+    5
+}
+
+impl KeypadConfig {
+    /// The number of keys on the keypad
+    ///
+    /// Guaranteed to fit into a `u8` for a validated config
+    pub fn key_count(&self) -> u8 {
+        self.rows.saturating_mul(self.columns)
+    }
+
+    /// Check the grid and the keymap of the default page, returning whether the grid is valid
+    fn validate(&self, errors: &mut Vec<ConfigError>) -> bool {
+        let key_count = u16::from(self.rows) * u16::from(self.columns);
+        if key_count == 0 || key_count > u16::from(u8::MAX) {
+            errors.push(ConfigError::InvalidGrid {
+                rows: self.rows,
+                columns: self.columns,
+            });
+            return false;
+        }
+
+        self.pads
+            .validate(crate::konst::DEFAULT_PAGE, self.rows, self.columns, errors);
+        true
+    }
 }
 
+/// The configuration of the individual pads of one page, named `pad_{row}_{column}`
 #[derive(Debug, serde::Deserialize)]
-#[cfg_attr(test, derive(PartialEq, Eq, serde::Serialize))]
+#[serde(transparent)]
+pub struct Keymap(pub std::collections::HashMap<String, PadConfig>);
+
+impl Keymap {
+    pub fn pad(&self, row: u8, column: u8) -> Option<&PadConfig> {
+        self.0.get(&Self::pad_name(row, column))
+    }
+
+    fn pad_name(row: u8, column: u8) -> String {
+        format!("pad_{row}_{column}")
+    }
+
+    /// Add an empty configuration for all pads that are not configured
+    fn insert_missing_pads(&mut self, rows: u8, columns: u8) {
+        for row in 0..rows {
+            for column in 0..columns {
+                self.0.entry(Self::pad_name(row, column)).or_default();
+            }
+        }
+    }
+
+    fn validate(&self, page: &str, rows: u8, columns: u8, errors: &mut Vec<ConfigError>) {
+        for row in 0..rows {
+            for column in 0..columns {
+                if self.pad(row, column).is_none() {
+                    errors.push(ConfigError::MissingPad {
+                        page: page.to_string(),
+                        row,
+                        column,
+                    });
+                }
+            }
+        }
+
+        let is_valid_pad_name = |name: &str| {
+            (0..rows)
+                .flat_map(|row| (0..columns).map(move |column| (row, column)))
+                .any(|(row, column)| Self::pad_name(row, column) == name)
+        };
+
+        // Sorted, so that the errors are in a stable order
+        let pads = self.0.iter().collect::<std::collections::BTreeMap<_, _>>();
+        for (name, pad) in pads {
+            if !is_valid_pad_name(name) {
+                errors.push(ConfigError::UnknownPad {
+                    page: page.to_string(),
+                    pad: name.to_string(),
+                });
+            }
+
+            let mut multi_presses = pad.on_multi_press.iter().flatten();
+            if let Some(multi_press) = multi_presses.find(|m| m.count < 2) {
+                errors.push(ConfigError::InvalidPressCount {
+                    page: page.to_string(),
+                    pad: name.to_string(),
+                    count: multi_press.count,
+                });
+            }
+        }
+    }
+}
+
+/// The configuration of a pad, a template or the defaults of all pads
+///
+/// All fields are optional. Fields that are not set are taken from the template the pad
+/// `extends`, then from `[keypad.defaults]`. Colors that are not set anywhere are black.
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+#[cfg_attr(test, derive(PartialEq, serde::Serialize))]
+#[serde(default)]
 pub struct PadConfig {
-    pub released: [u8; 3],
-    pub pressed: [u8; 3],
-    pub alternative: [u8; 3],
-    pub on_press: Vec<OnPressAction>,
-    pub on_release: Vec<OnReleaseAction>,
+    /// The name of a template in `[templates]` to take unset fields from
+    pub extends: Option<String>,
+
+    pub released: Option<crate::util::Rgb>,
+    pub pressed: Option<crate::util::Rgb>,
+    pub alternative: Option<crate::util::Rgb>,
+    pub on_press: Option<Vec<ActionConfig>>,
+    pub on_release: Option<Vec<ActionConfig>>,
+
+    /// Actions to execute when the pad is held down for at least `long_press_threshold`
+    ///
+    /// If a pad has long press actions, its `on_press` actions are only executed once the pad is
+    /// released before the threshold elapsed.
+    pub on_long_press: Option<Vec<ActionConfig>>,
+
+    /// How long a pad has to be held down to count as a long press
+    #[serde(with = "humantime_serde::option")]
+    pub long_press_threshold: Option<std::time::Duration>,
+
+    /// Actions to execute when the pad is pressed twice within `tap_window`
+    pub on_double_press: Option<Vec<ActionConfig>>,
+
+    /// Actions to execute when the pad is pressed a number of times within `tap_window`
+    ///
+    /// If a pad has multi press actions, its `on_press` actions are only executed once the
+    /// `tap_window` closed after a single press.
+    pub on_multi_press: Option<Vec<MultiPressConfig>>,
+
+    /// How long to wait for the next press before a series of presses is considered complete
+    #[serde(with = "humantime_serde::option")]
+    pub tap_window: Option<std::time::Duration>,
+
+    /// Color the pad according to the state that is published on an MQTT topic
+    pub state_topic: Option<StateTopicConfig>,
+
+    /// A human-readable name of the pad, included in its events
+    pub label: Option<String>,
+
+    /// Animate the colors of the pad
+    pub effect: Option<EffectConfig>,
+}
+
+impl PadConfig {
+    /// Take all fields that are not set from `base`
+    fn merge(self, base: &PadConfig) -> PadConfig {
+        PadConfig {
+            extends: None,
+            released: self.released.or(base.released),
+            pressed: self.pressed.or(base.pressed),
+            alternative: self.alternative.or(base.alternative),
+            on_press: self.on_press.or_else(|| base.on_press.clone()),
+            on_release: self.on_release.or_else(|| base.on_release.clone()),
+            on_long_press: self.on_long_press.or_else(|| base.on_long_press.clone()),
+            long_press_threshold: self.long_press_threshold.or(base.long_press_threshold),
+            on_double_press: self
+                .on_double_press
+                .or_else(|| base.on_double_press.clone()),
+            on_multi_press: self.on_multi_press.or_else(|| base.on_multi_press.clone()),
+            tap_window: self.tap_window.or(base.tap_window),
+            state_topic: self.state_topic.or_else(|| base.state_topic.clone()),
+            label: self.label.or_else(|| base.label.clone()),
+            effect: self.effect.or_else(|| base.effect.clone()),
+        }
+    }
+
+    /// Merge the templates the pad extends into it, the closest template taking precedence
+    fn resolve_templates(
+        mut self,
+        templates: &std::collections::BTreeMap<String, PadConfig>,
+        chain: &mut Vec<String>,
+    ) -> Result<PadConfig, ConfigError> {
+        let Some(name) = self.extends.take() else {
+            return Ok(self);
+        };
+        if chain.contains(&name) {
+            let mut cycle = chain.clone();
+            cycle.push(name);
+            return Err(ConfigError::TemplateCycle(cycle));
+        }
+        let Some(template) = templates.get(&name) else {
+            return Err(ConfigError::UnknownTemplate(name));
+        };
+
+        chain.push(name);
+        let template = template.clone().resolve_templates(templates, chain)?;
+        Ok(self.merge(&template))
+    }
 }
 
+/// An animation of the colors of a single pad
+#[derive(Clone, Debug, serde::Deserialize)]
+#[cfg_attr(test, derive(PartialEq, serde::Serialize))]
+pub enum EffectConfig {
+    /// Fade from the color of the pad through all colors and back, one color per period
+    Fade {
+        colors: Vec<crate::util::Rgb>,
+        #[serde(with = "humantime_serde")]
+        period: std::time::Duration,
+    },
+
+    /// Fade the color of the pad out and in again
+    Breathe {
+        #[serde(with = "humantime_serde")]
+        period: std::time::Duration,
+    },
+
+    /// Jump to the color and fade back to the color of the pad, once per period
+    Pulse {
+        color: crate::util::Rgb,
+        #[serde(with = "humantime_serde")]
+        period: std::time::Duration,
+    },
+
+    /// Cycle through all hues
+    Rainbow {
+        #[serde(with = "humantime_serde")]
+        period: std::time::Duration,
+        #[serde(default = "default_rainbow_brightness")]
+        brightness: u8,
+    },
+}
+
+fn default_rainbow_brightness() -> u8 {
+    u8::MAX
+}
+
+/// An animation that spans the whole keypad, drawn over the colors of all pads
 #[derive(Clone, Debug, serde::Deserialize)]
 #[cfg_attr(test, derive(PartialEq, Eq, serde::Serialize))]
-pub enum OnPressAction {
-    ToggleBlinking,
-    ToggleBlinkingAlternativeColor,
+pub enum PadEffectConfig {
+    /// Light up one pad after the other, once per period
+    Chase {
+        color: crate::util::Rgb,
+        #[serde(with = "humantime_serde")]
+        period: std::time::Duration,
+        /// Run until stopped if not set
+        #[serde(default, with = "humantime_serde::option")]
+        duration: Option<std::time::Duration>,
+    },
+
+    /// A wave travelling from the left to the right column, once per period
+    Wave {
+        color: crate::util::Rgb,
+        #[serde(with = "humantime_serde")]
+        period: std::time::Duration,
+        /// Run until stopped if not set
+        #[serde(default, with = "humantime_serde::option")]
+        duration: Option<std::time::Duration>,
+    },
+
+    /// A ring spreading from the pad that started the effect
+    Ripple {
+        color: crate::util::Rgb,
+        #[serde(with = "humantime_serde")]
+        duration: std::time::Duration,
+    },
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[cfg_attr(test, derive(PartialEq, serde::Serialize))]
+pub struct StateTopicConfig {
+    pub topic: String,
 
-    Publish { topic: String, payload: String },
+    /// Dot-separated path of the value in a JSON payload, e.g. `attributes.brightness`
+    ///
+    /// If not set, the whole payload is used as value.
+    #[serde(default)]
+    pub json_path: Option<String>,
+
+    /// Colors for the values, the first matching entry is used
+    ///
+    /// If no entry matches, the colors of the pad are used.
+    pub mapping: Vec<StateMappingConfig>,
+}
+
+/// Colors that are used if the value matches all of the given conditions
+#[derive(Clone, Debug, serde::Deserialize)]
+#[cfg_attr(test, derive(PartialEq, serde::Serialize))]
+pub struct StateMappingConfig {
+    /// The value is exactly this string
+    #[serde(default)]
+    pub equals: Option<String>,
+
+    /// The value is a number that is at least this big
+    #[serde(default)]
+    pub min: Option<f64>,
+
+    /// The value is a number that is smaller than this
+    #[serde(default)]
+    pub max: Option<f64>,
+
+    #[serde(default)]
+    pub released: Option<crate::util::Rgb>,
+    #[serde(default)]
+    pub pressed: Option<crate::util::Rgb>,
 }
 
 #[derive(Clone, Debug, serde::Deserialize)]
 #[cfg_attr(test, derive(PartialEq, Eq, serde::Serialize))]
-pub enum OnReleaseAction {
-    Publish { topic: String, payload: String },
+pub struct MultiPressConfig {
+    /// The number of presses, at least two
+    pub count: u8,
+    pub actions: Vec<ActionConfig>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[cfg_attr(test, derive(PartialEq, Eq, serde::Serialize))]
+pub struct ChordConfig {
+    /// The indices of the keys that have to be held down together
+    pub keys: Vec<u8>,
+    pub actions: Vec<ActionConfig>,
+
+    /// Whether to skip the actions of the individual keys of the chord
+    ///
+    /// If set, the `on_press` actions of the key completing the chord are not executed, and no
+    /// other actions are executed for the keys of the chord until they are released.
+    #[serde(default)]
+    pub suppress_key_actions: bool,
+}
+
+fn default_http_method() -> String {
+    String::from("POST")
+}
+
+/// An action that can be bound to any trigger, e.g. pressing or releasing a pad
+#[derive(Clone, Debug, serde::Deserialize)]
+#[cfg_attr(test, derive(PartialEq, Eq, serde::Serialize))]
+pub enum ActionConfig {
+    ToggleBlinking,
+    ToggleBlinkingAlternativeColor,
+
+    /// Publish a message
+    ///
+    /// Topic and payload can contain `{{variable}}` placeholders for the variables `index`,
+    /// `row`, `col`, `duration_ms`, `timestamp`, `page`, `blinking` and `counter`.
+    Publish {
+        topic: String,
+        payload: String,
+
+        /// The QoS level, 0, 1 or 2
+        #[serde(default)]
+        qos: crate::mqtt::Qos,
+
+        #[serde(default)]
+        retain: bool,
+    },
+
+    SwitchPage {
+        name: String,
+    },
+    PushPage {
+        name: String,
+    },
+    PopPage,
+
+    /// Execute a command, killing it if it runs longer than `timeout`
+    Exec {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+        #[serde(default)]
+        env: std::collections::BTreeMap<String, String>,
+        #[serde(default, with = "humantime_serde::option")]
+        timeout: Option<std::time::Duration>,
+    },
+
+    /// Send an HTTP request, e.g. to a webhook
+    Http {
+        #[serde(default = "default_http_method")]
+        method: String,
+        url: String,
+        #[serde(default)]
+        headers: std::collections::BTreeMap<String, String>,
+        #[serde(default)]
+        body: Option<String>,
+        #[serde(default, with = "humantime_serde::option")]
+        timeout: Option<std::time::Duration>,
+    },
+
+    /// Start an effect on the whole keypad, replacing the current one
+    StartEffect(PadEffectConfig),
+    StopEffect,
+
+    /// Set the brightness of the keypad in percent
+    SetBrightness(u8),
+    BrightnessUp,
+    BrightnessDown,
 }
 
 #[cfg(test)]
@@ -120,11 +927,20 @@ mod tests {
         assert_eq!(
             config,
             crate::config::PadConfig {
-                released: [0, 0, 0],
-                pressed: [0, 0, 0],
-                alternative: [0, 0, 0],
-                on_press: vec![crate::config::OnPressAction::ToggleBlinking],
-                on_release: vec![]
+                extends: None,
+                released: Some(crate::util::Rgb::from([0, 0, 0])),
+                pressed: Some(crate::util::Rgb::from([0, 0, 0])),
+                alternative: Some(crate::util::Rgb::from([0, 0, 0])),
+                on_press: Some(vec![crate::config::ActionConfig::ToggleBlinking]),
+                on_release: Some(vec![]),
+                on_long_press: None,
+                long_press_threshold: None,
+                on_double_press: None,
+                on_multi_press: None,
+                tap_window: None,
+                state_topic: None,
+                label: None,
+                effect: None,
             }
         );
     }
@@ -143,14 +959,25 @@ mod tests {
         "#;
 
         let expected = crate::config::PadConfig {
-            released: [0, 0, 0],
-            pressed: [0, 0, 0],
-            alternative: [0, 0, 0],
-            on_press: vec![crate::config::OnPressAction::Publish {
+            extends: None,
+            released: Some(crate::util::Rgb::from([0, 0, 0])),
+            pressed: Some(crate::util::Rgb::from([0, 0, 0])),
+            alternative: Some(crate::util::Rgb::from([0, 0, 0])),
+            on_press: Some(vec![crate::config::ActionConfig::Publish {
                 topic: String::from("foo"),
                 payload: String::from("bar"),
-            }],
-            on_release: vec![],
+                qos: crate::mqtt::Qos::AtMost,
+                retain: false,
+            }]),
+            on_release: Some(vec![]),
+            on_long_press: None,
+            long_press_threshold: None,
+            on_double_press: None,
+            on_multi_press: None,
+            tap_window: None,
+            state_topic: None,
+            label: None,
+            effect: None,
         };
 
         let config: crate::config::PadConfig = toml::from_str(config_str).unwrap_or_else(|_| {
@@ -167,4 +994,646 @@ mod tests {
             toml::to_string(&expected).unwrap(),
         );
     }
+
+    #[test]
+    fn test_publish_qos_and_retain() {
+        let action = |config_str: &str| toml::from_str::<crate::config::ActionConfig>(config_str);
+
+        let config_str = r#"
+        [Publish]
+        topic = "foo"
+        payload = "bar"
+        qos = 1
+        retain = true
+        "#;
+        assert_eq!(
+            action(config_str).unwrap(),
+            crate::config::ActionConfig::Publish {
+                topic: String::from("foo"),
+                payload: String::from("bar"),
+                qos: crate::mqtt::Qos::AtLeast,
+                retain: true,
+            }
+        );
+
+        assert!(action(&config_str.replace("qos = 1", "qos = 3")).is_err());
+
+        let config_str = r#"
+        mqtt_broker_addr = "localhost"
+        mqtt_broker_port = 1883
+        mqtt_subscribe_prefix = "keypad"
+        mqtt_control_prefix = "keypad/control"
+        interval_duration = "1s"
+
+        [keypad]
+        rows = 1
+        columns = 1
+        pad_0_0 = { on_double_press = [{ Publish = { topic = "foo", payload = "bar", qos = 2, retain = true } }] }
+        "#;
+        let config = crate::config::Config::parse(config_str).unwrap();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_keypad_config_grid() {
+        let config_str = r#"
+        rows = 1
+        columns = 2
+
+        [pad_0_0]
+        released = [0,0,0]
+        pressed = [0,0,0]
+        alternative = [0,0,0]
+        on_press = []
+        on_release = []
+
+        [pad_0_1]
+        released = [1,1,1]
+        pressed = [0,0,0]
+        alternative = [0,0,0]
+        on_press = []
+        on_release = []
+        "#;
+        let config: crate::config::KeypadConfig = toml::from_str(config_str).unwrap();
+
+        let mut errors = Vec::new();
+        assert!(config.validate(&mut errors));
+        assert!(errors.is_empty());
+        assert_eq!(config.key_count(), 2);
+        assert_eq!(
+            config.pads.pad(0, 1).unwrap().released,
+            Some(crate::util::Rgb::from([1, 1, 1]))
+        );
+        assert!(config.pads.pad(1, 0).is_none());
+    }
+
+    #[test]
+    fn test_keypad_config_grid_missing_pad() {
+        let config_str = r#"
+        rows = 2
+        columns = 1
+
+        [pad_0_0]
+        released = [0,0,0]
+        pressed = [0,0,0]
+        alternative = [0,0,0]
+        on_press = []
+        on_release = []
+        "#;
+        let config: crate::config::KeypadConfig = toml::from_str(config_str).unwrap();
+
+        let mut errors = Vec::new();
+        assert!(config.validate(&mut errors));
+        assert!(matches!(
+            errors.as_slice(),
+            [crate::config::ConfigError::MissingPad {
+                row: 1,
+                column: 0,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn test_pad_config_long_press() {
+        let config_str = r#"
+        released = [0,0,0]
+        pressed = [0,0,0]
+        alternative = [0,0,0]
+        on_press = ["ToggleBlinking"]
+        on_release = []
+        on_long_press = ["ToggleBlinkingAlternativeColor"]
+        long_press_threshold = "1s"
+        "#;
+        let config: crate::config::PadConfig = toml::from_str(config_str).unwrap();
+
+        assert_eq!(
+            config.on_long_press,
+            Some(vec![
+                crate::config::ActionConfig::ToggleBlinkingAlternativeColor
+            ])
+        );
+        assert_eq!(
+            config.long_press_threshold,
+            Some(std::time::Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn test_pad_config_multi_press() {
+        let config_str = r#"
+        released = [0,0,0]
+        pressed = [0,0,0]
+        alternative = [0,0,0]
+        on_press = []
+        on_release = []
+        on_double_press = ["ToggleBlinking"]
+        tap_window = "250ms"
+
+        [[on_multi_press]]
+        count = 3
+        actions = ["ToggleBlinkingAlternativeColor"]
+        "#;
+        let config: crate::config::PadConfig = toml::from_str(config_str).unwrap();
+
+        assert_eq!(
+            config.on_double_press,
+            Some(vec![crate::config::ActionConfig::ToggleBlinking])
+        );
+        assert_eq!(
+            config.on_multi_press,
+            Some(vec![crate::config::MultiPressConfig {
+                count: 3,
+                actions: vec![crate::config::ActionConfig::ToggleBlinkingAlternativeColor],
+            }])
+        );
+        assert_eq!(
+            config.tap_window,
+            Some(std::time::Duration::from_millis(250))
+        );
+    }
+
+    #[test]
+    fn test_chord_config() {
+        let config_str = r#"
+        keys = [0, 24]
+        actions = ["ToggleBlinking"]
+        suppress_key_actions = true
+        "#;
+        let config: crate::config::ChordConfig = toml::from_str(config_str).unwrap();
+
+        assert_eq!(
+            config,
+            crate::config::ChordConfig {
+                keys: vec![0, 24],
+                actions: vec![crate::config::ActionConfig::ToggleBlinking],
+                suppress_key_actions: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_groups_config() {
+        let config_str = r#"
+        mqtt_broker_addr = "localhost"
+        mqtt_broker_port = 1883
+        mqtt_subscribe_prefix = "keypad"
+        mqtt_control_prefix = "keypad/control"
+        interval_duration = "1s"
+
+        [keypad]
+        rows = 1
+        columns = 2
+        pad_0_0 = { released = [0,0,0], pressed = [0,0,0], alternative = [0,0,0], on_press = [], on_release = [] }
+        pad_0_1 = { released = [0,0,0], pressed = [0,0,0], alternative = [0,0,0], on_press = [], on_release = [] }
+
+        [groups]
+        lights = [0, 1]
+        "#;
+        let config: crate::config::Config = toml::from_str(config_str).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.groups.get("lights"), Some(&vec![0, 1]));
+
+        let config_str = config_str.replace("lights = [0, 1]", "lights = [0, 2]");
+        let config: crate::config::Config = toml::from_str(&config_str).unwrap();
+        assert!(matches!(
+            config.validate().unwrap_err().as_slice(),
+            [crate::config::ConfigError::KeyOutOfRange(2)]
+        ));
+
+        let config_str = config_str.replace("lights = [0, 2]", "\"all/lights\" = [0]");
+        let config: crate::config::Config = toml::from_str(&config_str).unwrap();
+        assert!(matches!(
+            config.validate().unwrap_err().as_slice(),
+            [crate::config::ConfigError::InvalidGroupName(_)]
+        ));
+    }
+
+    #[test]
+    fn test_validate_collects_all_errors() {
+        let config_str = r#"
+        mqtt_broker_addr = "localhost"
+        mqtt_broker_port = 1883
+        mqtt_subscribe_prefix = "keypad"
+        mqtt_control_prefix = "keypad/control"
+        mqtt_password = "secret"
+        interval_duration = "1s"
+
+        [animation]
+        frame_rate = 0
+
+        [keypad]
+        rows = 1
+        columns = 2
+        [keypad.defaults]
+
+        [pages.default]
+
+        [[chords]]
+        keys = [0, 2]
+        actions = []
+
+        [groups]
+        "all/lights" = [3]
+        "#;
+        let config = crate::config::Config::parse(config_str).unwrap();
+
+        let errors = config.validate().unwrap_err();
+        assert!(
+            matches!(
+                errors.as_slice(),
+                [
+                    crate::config::ConfigError::PasswordWithoutUsername,
+                    crate::config::ConfigError::InvalidFrameRate,
+                    crate::config::ConfigError::KeyOutOfRange(2),
+                    crate::config::ConfigError::KeyOutOfRange(3),
+                    crate::config::ConfigError::ReservedPageName(_),
+                    crate::config::ConfigError::InvalidGroupName(_),
+                ]
+            ),
+            "{errors:?}"
+        );
+    }
+
+    #[test]
+    fn test_mqtt_connection_options() {
+        let config = |connection: &str| {
+            let config_str = format!(
+                r#"
+                mqtt_broker_addr = "localhost"
+                mqtt_broker_port = 8883
+                mqtt_subscribe_prefix = "keypad"
+                mqtt_control_prefix = "keypad/control"
+                interval_duration = "1s"
+                {connection}
+
+                [keypad]
+                rows = 1
+                columns = 1
+                pad_0_0 = {{ released = [0,0,0], pressed = [0,0,0], alternative = [0,0,0], on_press = [], on_release = [] }}
+                "#
+            );
+            toml::from_str::<crate::config::Config>(&config_str).unwrap()
+        };
+
+        let valid = config(
+            r#"
+            mqtt_client_id = "keypad-util"
+            mqtt_username = "keypad"
+            mqtt_password_env = "KEYPAD_MQTT_PASSWORD"
+            mqtt_keepalive = "30s"
+            mqtt_tls = { ca_file = "/etc/ssl/ca.pem" }
+            "#,
+        );
+        assert_eq!(
+            valid.mqtt_keepalive,
+            Some(std::time::Duration::from_secs(30))
+        );
+        assert_eq!(valid.mqtt_client_id.as_deref(), Some("keypad-util"));
+        assert!(valid.validate().is_ok());
+        assert!(config("").validate().is_ok());
+
+        let without_username = config(r#"mqtt_password = "secret""#);
+        assert!(matches!(
+            without_username.validate().unwrap_err().as_slice(),
+            [crate::config::ConfigError::PasswordWithoutUsername]
+        ));
+
+        let ambiguous = config(
+            r#"
+            mqtt_username = "keypad"
+            mqtt_password = "secret"
+            mqtt_password_file = "/run/secrets/mqtt"
+            "#,
+        );
+        assert!(matches!(
+            ambiguous.validate().unwrap_err().as_slice(),
+            [crate::config::ConfigError::AmbiguousPassword]
+        ));
+
+        let incomplete =
+            config(r#"mqtt_tls = { client_certificate_file = "/etc/ssl/client.pem" }"#);
+        assert!(matches!(
+            incomplete.validate().unwrap_err().as_slice(),
+            [crate::config::ConfigError::IncompleteClientCertificate]
+        ));
+    }
+
+    #[test]
+    fn test_pad_config_effects() {
+        let config_str = r#"
+        released = [0,0,0]
+        pressed = [0,0,0]
+        alternative = [0,0,0]
+        on_release = []
+        effect = { Breathe = { period = "2s" } }
+
+        [[on_press]]
+        StartEffect = { Ripple = { color = [0, 0, 50], duration = "500ms" } }
+        "#;
+        let config: crate::config::PadConfig = toml::from_str(config_str).unwrap();
+
+        assert_eq!(
+            config.effect,
+            Some(crate::config::EffectConfig::Breathe {
+                period: std::time::Duration::from_secs(2)
+            })
+        );
+        assert_eq!(
+            config.on_press,
+            Some(vec![crate::config::ActionConfig::StartEffect(
+                crate::config::PadEffectConfig::Ripple {
+                    color: crate::util::Rgb::from([0, 0, 50]),
+                    duration: std::time::Duration::from_millis(500),
+                }
+            )])
+        );
+    }
+
+    #[test]
+    fn test_pages_config() {
+        let config_str = r#"
+        [media.pad_0_0]
+        released = [0,0,0]
+        pressed = [0,0,0]
+        alternative = [0,0,0]
+        on_press = ["PopPage"]
+        on_release = []
+        "#;
+        let pages: std::collections::BTreeMap<String, crate::config::Keymap> =
+            toml::from_str(config_str).unwrap();
+
+        let media = pages.get("media").unwrap();
+        let mut errors = Vec::new();
+        media.validate("media", 1, 1, &mut errors);
+        assert!(errors.is_empty());
+        assert_eq!(
+            media.pad(0, 0).unwrap().on_press,
+            Some(vec![crate::config::ActionConfig::PopPage])
+        );
+    }
+
+    #[test]
+    fn test_pad_config_state_topic() {
+        let config_str = r#"
+        released = [0,0,0]
+        pressed = [0,0,0]
+        alternative = [0,0,0]
+        on_press = []
+        on_release = []
+
+        [state_topic]
+        topic = "home/livingroom/light/state"
+        json_path = "state"
+
+        [[state_topic.mapping]]
+        equals = "ON"
+        released = [0,50,0]
+
+        [[state_topic.mapping]]
+        min = 10.0
+        max = 20.5
+        pressed = [50,0,0]
+        "#;
+        let config: crate::config::PadConfig = toml::from_str(config_str).unwrap();
+
+        assert_eq!(
+            config.state_topic,
+            Some(crate::config::StateTopicConfig {
+                topic: String::from("home/livingroom/light/state"),
+                json_path: Some(String::from("state")),
+                mapping: vec![
+                    crate::config::StateMappingConfig {
+                        equals: Some(String::from("ON")),
+                        min: None,
+                        max: None,
+                        released: Some(crate::util::Rgb::from([0, 50, 0])),
+                        pressed: None,
+                    },
+                    crate::config::StateMappingConfig {
+                        equals: None,
+                        min: Some(10.0),
+                        max: Some(20.5),
+                        released: None,
+                        pressed: Some(crate::util::Rgb::from([50, 0, 0])),
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn test_pad_config_exec() {
+        let config_str = r#"
+        released = [0,0,0]
+        pressed = [0,0,0]
+        alternative = [0,0,0]
+        on_press = []
+
+        [[on_release]]
+        [on_release.Exec]
+        command = "wakeonlan"
+        args = ["00:11:22:33:44:55"]
+        env = { "LANG" = "C" }
+        timeout = "5s"
+        "#;
+        let config: crate::config::PadConfig = toml::from_str(config_str).unwrap();
+
+        assert_eq!(
+            config.on_release,
+            Some(vec![crate::config::ActionConfig::Exec {
+                command: String::from("wakeonlan"),
+                args: vec![String::from("00:11:22:33:44:55")],
+                env: [(String::from("LANG"), String::from("C"))].into(),
+                timeout: Some(std::time::Duration::from_secs(5)),
+            }])
+        );
+    }
+
+    #[test]
+    fn test_pad_config_http() {
+        let config_str = r#"
+        released = [0,0,0]
+        pressed = [0,0,0]
+        alternative = [0,0,0]
+        on_release = []
+
+        [[on_press]]
+        [on_press.Http]
+        url = "http://homeassistant.local:8123/api/webhook/keypad"
+        headers = { "Content-Type" = "application/json" }
+        body = '{"key": 0}'
+        "#;
+        let config: crate::config::PadConfig = toml::from_str(config_str).unwrap();
+
+        assert_eq!(
+            config.on_press,
+            Some(vec![crate::config::ActionConfig::Http {
+                method: String::from("POST"),
+                url: String::from("http://homeassistant.local:8123/api/webhook/keypad"),
+                headers: [(
+                    String::from("Content-Type"),
+                    String::from("application/json")
+                )]
+                .into(),
+                body: Some(String::from(r#"{"key": 0}"#)),
+                timeout: None,
+            }])
+        );
+    }
+
+    #[test]
+    fn test_pad_config_release_actions() {
+        let config_str = r#"
+        released = [0,0,0]
+        pressed = [0,0,0]
+        alternative = [0,0,0]
+        on_press = []
+        on_release = ["ToggleBlinking", "PopPage"]
+        "#;
+        let config: crate::config::PadConfig = toml::from_str(config_str).unwrap();
+
+        assert_eq!(
+            config.on_release,
+            Some(vec![
+                crate::config::ActionConfig::ToggleBlinking,
+                crate::config::ActionConfig::PopPage
+            ])
+        );
+    }
+
+    #[test]
+    fn test_color_formats_and_palette() {
+        let config_str = r##"
+        mqtt_broker_addr = "localhost"
+        mqtt_broker_port = 1883
+        mqtt_subscribe_prefix = "keypad"
+        mqtt_control_prefix = "keypad/control"
+        interval_duration = "1s"
+
+        [palette]
+        accent = "#ff8800"
+        red = [200, 0, 0]
+
+        [keypad]
+        rows = 1
+        columns = 2
+        pad_0_0 = { released = "accent", pressed = "#f00", alternative = "Orange", on_press = [], on_release = [] }
+        pad_0_1 = { released = "red", pressed = { h = 120, s = 1.0, v = 0.5 }, alternative = [1,2,3], on_press = [], on_release = [] }
+        "##;
+        let config = crate::config::Config::parse(config_str).unwrap();
+
+        let pad = config.keypad.pads.pad(0, 0).unwrap();
+        assert_eq!(pad.released, Some(crate::util::Rgb::from([255, 136, 0])));
+        assert_eq!(pad.pressed, Some(crate::util::Rgb::from([255, 0, 0])));
+        assert_eq!(pad.alternative, Some(crate::util::Rgb::from([255, 165, 0])));
+
+        // Palette entries take precedence over named colors
+        let pad = config.keypad.pads.pad(0, 1).unwrap();
+        assert_eq!(pad.released, Some(crate::util::Rgb::from([200, 0, 0])));
+        assert_eq!(pad.pressed, Some(crate::util::Rgb::from([0, 128, 0])));
+        assert_eq!(pad.alternative, Some(crate::util::Rgb::from([1, 2, 3])));
+
+        let error = |config_str: String| match crate::config::Config::parse(&config_str) {
+            Err(crate::config::ConfigError::Toml(error)) => error.message().to_string(),
+            result => panic!("Expected a toml error, got {result:?}"),
+        };
+        assert_eq!(
+            error(config_str.replace("\"accent\"", "\"unknown\"")),
+            "Unknown color 'unknown', neither a named color nor in the palette"
+        );
+        assert_eq!(
+            error(config_str.replace("\"#f00\"", "\"#ff00\"")),
+            "Hex color '#ff00' must have 3 or 6 digits"
+        );
+    }
+
+    #[test]
+    fn test_defaults_and_templates() {
+        let config_str = r#"
+        mqtt_broker_addr = "localhost"
+        mqtt_broker_port = 1883
+        mqtt_subscribe_prefix = "keypad"
+        mqtt_control_prefix = "keypad/control"
+        interval_duration = "1s"
+
+        [templates.light]
+        pressed = [50, 0, 0]
+        on_press = ["ToggleBlinking"]
+
+        [templates.kitchen]
+        extends = "light"
+        label = "Kitchen"
+
+        [keypad]
+        rows = 1
+        columns = 3
+
+        [keypad.defaults]
+        released = [0, 50, 0]
+        pressed = [0, 0, 50]
+        on_release = ["PopPage"]
+
+        [keypad.pad_0_0]
+        extends = "kitchen"
+        on_release = []
+
+        [pages.media]
+        pad_0_1 = { released = [1, 1, 1] }
+        "#;
+        let config = crate::config::Config::parse(config_str).unwrap();
+        assert!(config.validate().is_ok());
+
+        // The pad takes precedence over its templates, which take precedence over the defaults
+        let pad = config.keypad.pads.pad(0, 0).unwrap();
+        assert_eq!(pad.released, Some(crate::util::Rgb::from([0, 50, 0])));
+        assert_eq!(pad.pressed, Some(crate::util::Rgb::from([50, 0, 0])));
+        assert_eq!(pad.alternative, None);
+        assert_eq!(pad.label.as_deref(), Some("Kitchen"));
+        assert_eq!(
+            pad.on_press,
+            Some(vec![crate::config::ActionConfig::ToggleBlinking])
+        );
+        assert_eq!(pad.on_release, Some(vec![]));
+
+        // Pads that are not configured use the defaults
+        let media = config.pages.get("media").unwrap();
+        assert_eq!(
+            media.pad(0, 1).unwrap().released,
+            Some(crate::util::Rgb::from([1, 1, 1]))
+        );
+        assert_eq!(
+            media.pad(0, 2).unwrap().on_release,
+            Some(vec![crate::config::ActionConfig::PopPage])
+        );
+
+        let cycle_str = config_str.replace("extends = \"light\"", "extends = \"kitchen\"");
+        assert!(matches!(
+            crate::config::Config::parse(&cycle_str),
+            Err(crate::config::ConfigError::TemplateCycle(cycle)) if cycle == ["kitchen", "kitchen"]
+        ));
+
+        // Templates that no pad extends are resolved as well
+        let unused_str = config_str.replace("extends = \"kitchen\"", "");
+        let cycle_str = unused_str.replace(
+            "[templates.light]",
+            "[templates.light]\nextends = \"kitchen\"",
+        );
+        let error = crate::config::Config::parse(&cycle_str).unwrap_err();
+        assert!(matches!(
+            &error,
+            crate::config::ConfigError::TemplateCycle(cycle)
+                if *cycle == ["kitchen", "light", "kitchen"]
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Templates extend each other in a cycle: kitchen -> light -> kitchen"
+        );
+
+        let unknown_str = unused_str.replace("extends = \"light\"", "extends = \"lights\"");
+        assert!(matches!(
+            crate::config::Config::parse(&unknown_str),
+            Err(crate::config::ConfigError::UnknownTemplate(name)) if name == "lights"
+        ));
+    }
 }
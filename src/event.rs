@@ -0,0 +1,51 @@
+/// A human-readable key event, published on `{mqtt_event_prefix}/events/key/{index}`
+#[derive(Debug, serde::Serialize)]
+pub struct KeyEvent<'a> {
+    pub event: KeyEventKind,
+    pub row: u8,
+    pub col: u8,
+    pub label: Option<&'a str>,
+    pub page: &'a str,
+
+    /// The number of taps of a multi press
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub taps: Option<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyEventKind {
+    Pressed,
+    Released,
+    LongPress,
+    DoublePress,
+    MultiPress,
+}
+
+/// The topic that events of the key with the passed index are published on
+pub fn topic(event_prefix: &str, index: u8) -> String {
+    format!("{event_prefix}/events/key/{index}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyEvent;
+    use super::KeyEventKind;
+
+    #[test]
+    fn test_key_event_json() {
+        let event = KeyEvent {
+            event: KeyEventKind::LongPress,
+            row: 1,
+            col: 3,
+            label: Some("Kitchen"),
+            page: "default",
+            taps: None,
+        };
+
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"long_press","row":1,"col":3,"label":"Kitchen","page":"default"}"#
+        );
+    }
+}
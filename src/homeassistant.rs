@@ -0,0 +1,171 @@
+/// The payload Home Assistant publishes on `{discovery_prefix}/status` when it comes online
+const BIRTH_PAYLOAD: &[u8] = b"online";
+
+/// All event types that keys can emit, see [`crate::event::KeyEventKind`]
+const EVENT_TYPES: [&str; 5] = [
+    "pressed",
+    "released",
+    "long_press",
+    "double_press",
+    "multi_press",
+];
+
+/// The topic Home Assistant announces itself on
+pub fn status_topic(config: &crate::config::HomeAssistantConfig) -> String {
+    format!("{}/status", config.discovery_prefix)
+}
+
+/// Whether the message is the birth message of Home Assistant, after which discovery payloads
+/// have to be published again
+pub fn is_birth_message(message: &crate::mqtt::Message) -> bool {
+    message.payload == BIRTH_PAYLOAD
+}
+
+/// Publish the discovery payloads of all keys, if Home Assistant discovery is enabled
+pub async fn publish_discovery(mqtt: &crate::mqtt::Client, config: &crate::config::Config) {
+    let messages = discovery_messages(config);
+    tracing::info!(n = messages.len(), "Publishing Home Assistant discovery");
+
+    // Discovery is retained, but is also published again whenever Home Assistant comes online in
+    // case the broker lost it
+    futures::future::join_all(messages.into_iter().map(|(topic, payload)| {
+        let qos = crate::mqtt::Qos::AtLeast;
+        crate::mqtt::publish(mqtt, topic, payload.to_string(), qos, true)
+    }))
+    .await;
+}
+
+/// The discovery topics and payloads of all keys
+///
+/// Every key gets an `event` entity for its events and a `switch` entity for its blinking state,
+/// which is controlled via the control topic of the key.
+fn discovery_messages(config: &crate::config::Config) -> Vec<(String, serde_json::Value)> {
+    let Some(homeassistant) = config.homeassistant.as_ref() else {
+        return Vec::new();
+    };
+    let Some(event_prefix) = config.mqtt_event_prefix.as_deref() else {
+        return Vec::new();
+    };
+
+    let node_id = &homeassistant.node_id;
+    let device = serde_json::json!({
+        "identifiers": [node_id],
+        "name": homeassistant.device_name.as_deref().unwrap_or(node_id),
+        "model": env!("CARGO_PKG_NAME"),
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+
+    let keypad = &config.keypad;
+    (0..keypad.rows)
+        .flat_map(|row| (0..keypad.columns).map(move |column| (row, column)))
+        .flat_map(|(row, column)| {
+            let index = row * keypad.columns + column;
+            let name = keypad
+                .pads
+                .pad(row, column)
+                .and_then(|pad| pad.label.clone())
+                .unwrap_or_else(|| format!("Key {index}"));
+            let object_id = format!("key_{index}");
+
+            let event = serde_json::json!({
+                "name": name,
+                "unique_id": format!("{node_id}_{object_id}_event"),
+                "device": device,
+                "state_topic": crate::event::topic(event_prefix, index),
+                "event_types": EVENT_TYPES,
+                "value_template": "{{ {'event_type': value_json.event} | to_json }}",
+            });
+
+            let control_topic = format!("{}/key/{index}", config.mqtt_control_prefix);
+            let set_blinking = |blinking: bool| {
+                serde_json::json!({ "actions": [{ "SetBlinking": blinking }] }).to_string()
+            };
+            let mut switch = serde_json::json!({
+                "name": format!("{name} blinking"),
+                "unique_id": format!("{node_id}_{object_id}_blinking"),
+                "device": device,
+                "command_topic": control_topic,
+                "payload_on": set_blinking(true),
+                "payload_off": set_blinking(false),
+            });
+            match config.mqtt_state_prefix.as_deref() {
+                Some(state_prefix) => {
+                    switch["state_topic"] = crate::report::key_topic(state_prefix, index).into();
+                    switch["value_template"] =
+                        "{{ 'ON' if value_json.blinking else 'OFF' }}".into();
+                }
+                None => switch["optimistic"] = true.into(),
+            }
+
+            let topic = |component: &str| {
+                format!(
+                    "{}/{component}/{node_id}/{object_id}/config",
+                    homeassistant.discovery_prefix
+                )
+            };
+            let mut messages = [(topic("event"), event), (topic("switch"), switch)];
+            if let Some(availability_topic) = config.mqtt_availability_topic.as_deref() {
+                for (_, payload) in messages.iter_mut() {
+                    payload["availability_topic"] = availability_topic.into();
+                }
+            }
+            messages
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::discovery_messages;
+
+    #[test]
+    fn test_discovery_messages() {
+        let config_str = r#"
+        mqtt_broker_addr = "localhost"
+        mqtt_broker_port = 1883
+        mqtt_subscribe_prefix = "keypad"
+        mqtt_control_prefix = "keypad/control"
+        mqtt_event_prefix = "keypad"
+        mqtt_availability_topic = "keypad/availability"
+        interval_duration = "1s"
+
+        [keypad]
+        rows = 1
+        columns = 2
+        pad_0_0 = { released = [0,0,0], pressed = [0,0,0], alternative = [0,0,0], on_press = [], on_release = [], label = "Kitchen" }
+        pad_0_1 = { released = [0,0,0], pressed = [0,0,0], alternative = [0,0,0], on_press = [], on_release = [] }
+
+        [homeassistant]
+        "#;
+        let config: crate::config::Config = toml::from_str(config_str).unwrap();
+
+        let messages = discovery_messages(&config);
+        let topics = messages
+            .iter()
+            .map(|(topic, _)| topic.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/event/keypad/key_0/config",
+                "homeassistant/switch/keypad/key_0/config",
+                "homeassistant/event/keypad/key_1/config",
+                "homeassistant/switch/keypad/key_1/config",
+            ]
+        );
+
+        let (_, event) = &messages[0];
+        assert_eq!(event["name"], "Kitchen");
+        assert_eq!(event["state_topic"], "keypad/events/key/0");
+
+        let (_, switch) = &messages[3];
+        assert_eq!(switch["name"], "Key 1 blinking");
+        assert_eq!(switch["command_topic"], "keypad/control/key/1");
+        assert_eq!(
+            switch["payload_on"],
+            r#"{"actions":[{"SetBlinking":true}]}"#
+        );
+        assert_eq!(switch["optimistic"], true);
+        assert_eq!(switch["availability_topic"], "keypad/availability");
+    }
+}
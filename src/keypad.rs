@@ -1,255 +1,1082 @@
-use cloudmqtt::CloudmqttClient;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use tokio::time::Instant;
 
 use crate::config::PadConfig;
 
+pub(crate) mod animation;
+
 #[derive(Clone, Debug)]
 pub struct KeypadState {
-    rows: [Row; 5],
+    /// All pages, the default page first
+    pages: Vec<Page>,
+    active_page: usize,
+    /// Pages to return to with `PageRequest::Pop`
+    page_stack: Vec<usize>,
+    columns: u8,
+    /// Named groups of key indices that can be targeted by control packets
+    groups: BTreeMap<String, Vec<u8>>,
+
+    chords: Vec<Chord>,
+    /// The indices of all keys that are currently held down
+    pressed_keys: BTreeSet<u8>,
+    /// The indices of held keys whose actions are suppressed, e.g. because they are part of a chord
+    suppressed_keys: BTreeSet<u8>,
+
+    /// The last state payloads that were published, by topic
+    published_states: BTreeMap<String, Vec<u8>>,
+
+    /// The time between two frames while effects are running
+    frame_interval: Duration,
+    last_frame: Instant,
+    /// The point in time all key effects are timed from, so that they run in sync
+    animation_epoch: Instant,
+    pad_effect: Option<animation::RunningPadEffect>,
+
+    brightness: crate::brightness::Brightness,
 }
 
 impl KeypadState {
-    pub fn from_config(config: &crate::config::Config) -> Self {
+    /// Errors of actions that finish in the background are sent to `action_errors`
+    pub fn from_config(
+        config: &crate::config::Config,
+        action_errors: &crate::action::ActionErrors,
+    ) -> Self {
+        let keypad = &config.keypad;
+
+        let default_page = Page::from_keymap(
+            crate::konst::DEFAULT_PAGE,
+            &keypad.pads,
+            config,
+            action_errors,
+        );
+        let pages = std::iter::once(default_page)
+            .chain(
+                config
+                    .pages
+                    .iter()
+                    .map(|(name, keymap)| Page::from_keymap(name, keymap, config, action_errors)),
+            )
+            .collect();
+
         Self {
-            rows: [
-                Row([
-                    &config.keypad.pad_0_0,
-                    &config.keypad.pad_0_1,
-                    &config.keypad.pad_0_2,
-                    &config.keypad.pad_0_3,
-                    &config.keypad.pad_0_4,
-                ]
-                .into_iter()
-                .map(|pad: &PadConfig| KeyState::from(pad))
-                .collect()),
-                Row([
-                    &config.keypad.pad_1_0,
-                    &config.keypad.pad_1_1,
-                    &config.keypad.pad_1_2,
-                    &config.keypad.pad_1_3,
-                    &config.keypad.pad_1_4,
-                ]
-                .into_iter()
-                .map(|pad: &PadConfig| KeyState::from(pad))
-                .collect()),
-                Row([
-                    &config.keypad.pad_2_0,
-                    &config.keypad.pad_2_1,
-                    &config.keypad.pad_2_2,
-                    &config.keypad.pad_2_3,
-                    &config.keypad.pad_2_4,
-                ]
-                .into_iter()
-                .map(|pad: &PadConfig| KeyState::from(pad))
-                .collect()),
-                Row([
-                    &config.keypad.pad_3_0,
-                    &config.keypad.pad_3_1,
-                    &config.keypad.pad_3_2,
-                    &config.keypad.pad_3_3,
-                    &config.keypad.pad_3_4,
-                ]
-                .into_iter()
-                .map(|pad: &PadConfig| KeyState::from(pad))
-                .collect()),
-                Row([
-                    &config.keypad.pad_4_0,
-                    &config.keypad.pad_4_1,
-                    &config.keypad.pad_4_2,
-                    &config.keypad.pad_4_3,
-                    &config.keypad.pad_4_4,
-                ]
-                .into_iter()
-                .map(|pad: &PadConfig| KeyState::from(pad))
-                .collect()),
-            ],
-        }
-    }
-
-    pub async fn publish(
+            pages,
+            active_page: 0,
+            page_stack: Vec::new(),
+            columns: keypad.columns,
+            groups: config.groups.clone(),
+
+            chords: config.chords.iter().map(Chord::from).collect(),
+            pressed_keys: BTreeSet::new(),
+            suppressed_keys: BTreeSet::new(),
+
+            published_states: BTreeMap::new(),
+
+            frame_interval: Duration::from_secs(1) / config.animation.frame_rate,
+            last_frame: Instant::now(),
+            animation_epoch: Instant::now(),
+            pad_effect: None,
+
+            brightness: crate::brightness::Brightness::from(&config.brightness),
+        }
+    }
+
+    /// Take over the runtime state from the state of a previous configuration
+    ///
+    /// Keys keep their blinking state if they exist on a page of the same name, and the active
+    /// page is kept if it still exists.
+    pub fn adopt_runtime_state(&mut self, previous: &KeypadState) {
+        for page in self.pages.iter_mut() {
+            let Some(previous_page) = previous.pages.iter().find(|p| p.name == page.name) else {
+                continue;
+            };
+
+            for (row, previous_row) in page.rows.iter_mut().zip(previous_page.rows.iter()) {
+                for (key_state, previous_key_state) in row.0.iter_mut().zip(previous_row.0.iter()) {
+                    key_state.adopt_runtime_state(previous_key_state);
+                }
+            }
+        }
+
+        let find_page = |index: &usize| {
+            let name = &previous.pages[*index].name;
+            self.pages.iter().position(|page| page.name == *name)
+        };
+        self.page_stack = previous.page_stack.iter().filter_map(find_page).collect();
+        self.active_page = find_page(&previous.active_page).unwrap_or_default();
+
+        self.animation_epoch = previous.animation_epoch;
+        self.pad_effect = previous.pad_effect.clone();
+        self.brightness.adopt_runtime_state(&previous.brightness);
+    }
+
+    /// Publish the state of all keys on the active page and of the keypad, if it changed
+    pub async fn publish_state(
         &mut self,
-        client: &cloudmqtt::CloudmqttClient,
+        client: &crate::mqtt::Client,
         config: &crate::config::Config,
     ) {
-        let mut bytes_pressed: Vec<u8> = Vec::with_capacity((3 * 25) + 3);
-        bytes_pressed.extend([0, 0, 0, 25]);
-
-        bytes_pressed.extend(
-            self.rows
-                .iter_mut()
-                .flat_map(|r| r.0.iter_mut())
-                .flat_map(|key_state| key_state.color_pressed().as_slice().into_iter()),
-        );
+        let Some(state_prefix) = config.mqtt_state_prefix.as_deref() else {
+            return;
+        };
 
-        let mut bytes_released: Vec<u8> = Vec::with_capacity((3 * 25) + 3);
-        bytes_released.extend([0, 0, 0, 25]);
+        let report = crate::report::KeypadReport {
+            active_page: &self.pages[self.active_page].name,
+            page_stack: self
+                .page_stack
+                .iter()
+                .map(|index| self.pages[*index].name.as_str())
+                .collect(),
+            brightness: self.brightness.level(),
+            keys: self
+                .rows()
+                .iter()
+                .flat_map(|r| r.0.iter())
+                .map(KeyState::report)
+                .collect(),
+        };
 
-        bytes_released.extend(
-            self.rows
-                .iter_mut()
-                .flat_map(|r| r.0.iter_mut())
-                .flat_map(|key_state| key_state.color_released().as_slice().into_iter()),
-        );
+        let payloads = report
+            .keys
+            .iter()
+            .map(|key| {
+                let topic = crate::report::key_topic(state_prefix, key.index);
+                (topic, serde_json::to_vec(key))
+            })
+            .chain(std::iter::once((
+                crate::report::keypad_topic(state_prefix),
+                serde_json::to_vec(&report),
+            )))
+            .collect::<Vec<_>>();
 
-        let pressed_pub = client.publish(
-            bytes_pressed,
+        let mut changed = Vec::new();
+        for (topic, payload) in payloads {
+            let payload = match payload {
+                Ok(payload) => payload,
+                Err(error) => {
+                    tracing::error!(?error, topic, "Failed to serialize state");
+                    continue;
+                }
+            };
+
+            if self.published_states.get(&topic) != Some(&payload) {
+                self.published_states.insert(topic.clone(), payload.clone());
+                changed.push((topic, payload));
+            }
+        }
+
+        tracing::debug!(n = changed.len(), "Publishing changed states");
+        // Retained, so that subscribers get the state without waiting for the next change
+        futures::future::join_all(changed.into_iter().map(|(topic, payload)| {
+            crate::mqtt::publish(client, topic, payload, crate::mqtt::Qos::AtMost, true)
+        }))
+        .await;
+    }
+
+    /// Publish all states again on the next call to [`KeypadState::publish_state`]
+    pub fn forget_published_state(&mut self) {
+        self.published_states.clear();
+    }
+
+    /// Update the colors of all keys that take their state from the topic, on all pages
+    pub fn apply_state_message(&mut self, topic: &str, payload: &[u8]) {
+        self.pages
+            .iter_mut()
+            .flat_map(|page| page.rows.iter_mut())
+            .flat_map(|r| r.0.iter_mut())
+            .filter(|key_state| {
+                key_state
+                    .state_topic
+                    .as_ref()
+                    .is_some_and(|state_topic| state_topic.topic == topic)
+            })
+            .for_each(|key_state| key_state.apply_state(payload));
+    }
+
+    fn rows(&self) -> &[Row] {
+        &self.pages[self.active_page].rows
+    }
+
+    fn rows_mut(&mut self) -> &mut [Row] {
+        &mut self.pages[self.active_page].rows
+    }
+
+    fn key_count(&self) -> u8 {
+        // Cannot overflow, the config validation ensures that there are at most 255 keys
+        (self.rows().len() * usize::from(self.columns)) as u8
+    }
+
+    /// Translate a key index into the index of the row and the index of the key within that row
+    fn locate(&self, index: u8) -> Option<(usize, u8)> {
+        let row = usize::from(index / self.columns);
+        (row < self.rows().len()).then_some((row, index % self.columns))
+    }
+
+    /// Get the state of the key with the passed index on the active page
+    fn key_mut(&mut self, index: u8) -> Option<&mut KeyState> {
+        let (row, key) = self.locate(index)?;
+        self.rows_mut()[row].0.get_mut(usize::from(key))
+    }
+
+    pub async fn publish(&mut self, client: &crate::mqtt::Client, config: &crate::config::Config) {
+        let now = Instant::now();
+        self.last_frame = now;
+        if self
+            .pad_effect
+            .take_if(|effect| effect.is_finished(now))
+            .is_some()
+        {
+            tracing::debug!("Effect finished");
+        }
+
+        let time = chrono::Local::now().time();
+        let bytes_pressed = self.frame(now, time, true);
+        let bytes_released = self.frame(now, time, false);
+
+        // Frames are retained, so that the keypad shows them right after it restarted
+        let pressed_pub = crate::mqtt::publish(
+            client,
             format!(
                 "{}/{}",
                 config.mqtt_subscribe_prefix,
                 crate::konst::KEYPAD_COLOR_RELEASED_TOPIC
             ),
+            bytes_pressed,
+            crate::mqtt::Qos::AtMost,
+            true,
         );
 
-        let released_pub = client.publish(
-            bytes_released,
+        let released_pub = crate::mqtt::publish(
+            client,
             format!(
                 "{}/{}",
                 config.mqtt_subscribe_prefix,
                 crate::konst::KEYPAD_COLOR_PRESSED_TOPIC
             ),
+            bytes_released,
+            crate::mqtt::Qos::AtMost,
+            true,
         );
 
         tokio::join!(pressed_pub, released_pub);
     }
 
-    pub async fn pressed(&mut self, index: u8, mqtt: &CloudmqttClient) {
+    /// The colors of all keys on the active page at a point in time, prefixed with the header
+    ///
+    /// `time` is the local time of the day, which the brightness depends on.
+    fn frame(&self, now: Instant, time: chrono::NaiveTime, pressed: bool) -> Vec<u8> {
+        let key_count = self.key_count();
+        let size = (self.rows().len() as u8, self.columns);
+        let elapsed = now.saturating_duration_since(self.animation_epoch);
+
+        let mut bytes: Vec<u8> = Vec::with_capacity((3 * usize::from(key_count)) + 4);
+        bytes.extend([0, 0, 0, key_count]);
+
+        for key_state in self.rows().iter().flat_map(|r| r.0.iter()) {
+            let color = if pressed {
+                key_state.color_pressed()
+            } else {
+                key_state.color_released()
+            };
+
+            let color = match self.pad_effect.as_ref() {
+                Some(effect) if key_state.flash.is_none() => {
+                    let position = (key_state.position.row, key_state.position.column);
+                    effect.apply(key_state.animate(color, elapsed), position, size, now)
+                }
+                _ => key_state.animate(color, elapsed),
+            };
+            let color = self.brightness.apply(color, time);
+            bytes.extend(color.as_slice());
+        }
+
+        bytes
+    }
+
+    /// When the next frame has to be published, if any effects are running on the active page
+    pub fn next_frame(&self) -> Option<Instant> {
+        let is_animating = self.pad_effect.is_some()
+            || self
+                .rows()
+                .iter()
+                .flat_map(|r| r.0.iter())
+                .any(|key_state| key_state.effect.is_some());
+
+        is_animating.then(|| self.last_frame + self.frame_interval)
+    }
+
+    /// Advance all blinking keys on the active page to their next blink state
+    pub fn advance_blinking(&mut self) {
+        self.rows_mut()
+            .iter_mut()
+            .flat_map(|r| r.0.iter_mut())
+            .for_each(KeyState::advance_blinking);
+    }
+
+    pub async fn pressed(&mut self, index: u8, mqtt: &crate::mqtt::Client) {
         tracing::debug!(?index, "Pressed");
-        match index {
-            0..=4 => self.rows[0].pressed(index % 5, mqtt).await,
-            5..=9 => self.rows[1].pressed(index % 5, mqtt).await,
-            10..=14 => self.rows[2].pressed(index % 5, mqtt).await,
-            15..=19 => self.rows[3].pressed(index % 5, mqtt).await,
-            20..=24 => self.rows[4].pressed(index % 5, mqtt).await,
-            other => tracing::warn!(index = other, "Out of index"),
+        if self.locate(index).is_none() {
+            tracing::warn!(?index, "Out of index");
+            return;
+        }
+        match self.hold_key(index) {
+            Some(chord) => self.chord_pressed(index, chord, mqtt).await,
+            None => {
+                let suppressed = self.suppressed_keys.contains(&index);
+                match self.key_mut(index) {
+                    Some(key_state) if suppressed => key_state.pressed_silently(mqtt).await,
+                    Some(key_state) => key_state.pressed(mqtt).await,
+                    None => {}
+                }
+            }
+        }
+
+        self.apply_requests();
+    }
+
+    /// Record that the key is held down, returning the chord it completes
+    ///
+    /// If several chords are completed by the key, the one with the most keys wins.
+    fn hold_key(&mut self, index: u8) -> Option<Chord> {
+        self.pressed_keys.insert(index);
+        self.chords
+            .iter()
+            .filter(|chord| chord.keys.contains(&index) && chord.keys.is_subset(&self.pressed_keys))
+            .max_by_key(|chord| chord.keys.len())
+            .cloned()
+    }
+
+    /// Record that the key was released, returning whether its actions are suppressed
+    fn release_key(&mut self, index: u8) -> bool {
+        self.pressed_keys.remove(&index);
+        self.suppressed_keys.remove(&index)
+    }
+
+    /// Suppress the actions of the keys of the chord until they are released, if configured
+    fn suppress_chord_keys(&mut self, chord: &Chord) {
+        if !chord.suppress_key_actions {
+            return;
         }
+
+        for chord_key in chord.keys.iter().copied() {
+            if let Some(key_state) = self.key_mut(chord_key) {
+                key_state.cancel_gestures();
+            }
+        }
+        self.suppressed_keys.extend(chord.keys.iter().copied());
     }
 
-    pub async fn released(&mut self, index: u8, mqtt: &CloudmqttClient) {
+    async fn chord_pressed(&mut self, index: u8, chord: Chord, mqtt: &crate::mqtt::Client) {
+        tracing::debug!(keys = ?chord.keys, "Chord pressed");
+        self.suppress_chord_keys(&chord);
+
+        if let Some(key_state) = self.key_mut(index) {
+            if chord.suppress_key_actions {
+                key_state.pressed_silently(mqtt).await;
+            } else {
+                key_state.pressed(mqtt).await;
+            }
+
+            key_state.run_actions(&chord.actions, mqtt).await;
+        }
+    }
+
+    pub async fn released(&mut self, index: u8, mqtt: &crate::mqtt::Client) {
         tracing::debug!(?index, "Released");
-        match index {
-            0..=4 => self.rows[0].released(index % 5, mqtt).await,
-            5..=9 => self.rows[1].released(index % 5, mqtt).await,
-            15..=19 => self.rows[3].released(index % 5, mqtt).await,
-            10..=14 => self.rows[2].released(index % 5, mqtt).await,
-            20..=24 => self.rows[4].released(index % 5, mqtt).await,
-            other => tracing::warn!(index = other, "Out of index"),
+        let suppressed = self.release_key(index);
+
+        match self.key_mut(index) {
+            Some(key_state) if suppressed => key_state.released_silently(mqtt).await,
+            Some(key_state) => key_state.released(mqtt).await,
+            None => tracing::warn!(?index, "Out of index"),
         }
+
+        self.apply_requests();
+    }
+
+    /// The next point in time at which `KeypadState::poll_timers` has to be called
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.rows()
+            .iter()
+            .flat_map(|r| r.0.iter())
+            .filter_map(KeyState::deadline)
+            .min()
     }
 
-    pub fn run_ctrl_action_on_key(&mut self, index: u8, action: crate::action::ControlAction) {
-        tracing::debug!(?index, "Running control action");
-        match index {
-            0..=4 => self.rows[0].run_ctrl_action_on_key(index % 5, action),
-            5..=9 => self.rows[1].run_ctrl_action_on_key(index % 5, action),
-            15..=19 => self.rows[3].run_ctrl_action_on_key(index % 5, action),
-            10..=14 => self.rows[2].run_ctrl_action_on_key(index % 5, action),
-            20..=24 => self.rows[4].run_ctrl_action_on_key(index % 5, action),
-            other => tracing::warn!(index = other, "Out of index"),
+    /// Run the actions of all gestures that are detected by time passing, e.g. long presses
+    pub async fn poll_timers(&mut self, mqtt: &crate::mqtt::Client) {
+        let now = Instant::now();
+        for key_state in self.rows_mut().iter_mut().flat_map(|r| r.0.iter_mut()) {
+            key_state.poll_timers(now, mqtt).await;
         }
+
+        self.apply_requests();
     }
-}
 
-#[derive(Clone, Debug)]
-struct Row(Vec<KeyState>);
+    pub fn run_ctrl_action(
+        &mut self,
+        target: &crate::action::ControlTarget,
+        action: crate::action::ControlAction,
+    ) {
+        tracing::debug!(?target, "Running control action");
+        if let Some(request) = action.page_request() {
+            self.apply_page_request(request);
+            return;
+        }
+        if let Some(request) = action.brightness_request() {
+            self.brightness.apply_request(request);
+            return;
+        }
 
-impl Row {
-    pub async fn pressed(&mut self, index: u8, mqtt: &CloudmqttClient) {
-        if index < 5 {
-            self.0[index as usize].pressed(mqtt).await
-        } else {
-            tracing::warn!(?index, "Row index out of range");
+        for index in self.target_keys(target) {
+            match self.key_mut(index) {
+                Some(key_state) => key_state.run_ctrl_action_on_key(action.clone()),
+                None => tracing::warn!(?index, "Out of index"),
+            }
         }
     }
 
-    pub async fn released(&mut self, index: u8, mqtt: &CloudmqttClient) {
-        if index < 5 {
-            self.0[index as usize].released(mqtt).await
-        } else {
-            tracing::warn!(?index, "Row index out of range");
+    /// The indices of the keys that a control target refers to
+    fn target_keys(&self, target: &crate::action::ControlTarget) -> Vec<u8> {
+        let rows = self.rows().len() as u8;
+        let columns = self.columns;
+        match target {
+            crate::action::ControlTarget::All => (0..self.key_count()).collect(),
+            crate::action::ControlTarget::Key(index) => vec![*index],
+            crate::action::ControlTarget::Row(row) if *row < rows => {
+                (0..columns).map(|column| row * columns + column).collect()
+            }
+            crate::action::ControlTarget::Column(column) if *column < columns => {
+                (0..rows).map(|row| row * columns + column).collect()
+            }
+            crate::action::ControlTarget::Group(name) => match self.groups.get(name) {
+                Some(keys) => keys.clone(),
+                None => {
+                    tracing::warn!(group = name, "Unknown group");
+                    Vec::new()
+                }
+            },
+            target => {
+                tracing::warn!(?target, "Out of index");
+                Vec::new()
+            }
         }
     }
 
-    fn run_ctrl_action_on_key(&mut self, index: u8, action: crate::action::ControlAction) {
-        if index < 5 {
-            self.0[index as usize].run_ctrl_action_on_key(action)
-        } else {
-            tracing::warn!(?index, "Row index out of range");
+    /// Set the brightness of the keypad in percent
+    pub fn set_brightness(&mut self, level: u8) {
+        self.brightness
+            .apply_request(crate::brightness::BrightnessRequest::Set(level));
+    }
+
+    /// Apply the page changes, effects and brightness changes requested by actions of keys on the
+    /// active page
+    fn apply_requests(&mut self) {
+        let now = Instant::now();
+        for key_state in self.pages[self.active_page]
+            .rows
+            .iter_mut()
+            .flat_map(|r| r.0.iter_mut())
+        {
+            if let Some(request) = key_state.brightness_request.take() {
+                self.brightness.apply_request(request);
+            }
+            match key_state.effect_request.take() {
+                Some(animation::EffectRequest::Start(effect)) => {
+                    let origin = (key_state.position.row, key_state.position.column);
+                    self.pad_effect = Some(animation::RunningPadEffect::new(effect, origin, now));
+                }
+                Some(animation::EffectRequest::Stop) => self.pad_effect = None,
+                None => {}
+            }
+        }
+
+        let requests = self
+            .rows_mut()
+            .iter_mut()
+            .flat_map(|r| r.0.iter_mut())
+            .filter_map(|key_state| key_state.page_request.take())
+            .collect::<Vec<_>>();
+
+        for request in requests {
+            self.apply_page_request(request);
+        }
+    }
+
+    fn apply_page_request(&mut self, request: PageRequest) {
+        tracing::debug!(?request, "Changing page");
+        let page = match request {
+            PageRequest::Switch(name) => self.page_index(&name),
+            PageRequest::Push(name) => {
+                let page = self.page_index(&name);
+                if page.is_some() {
+                    self.page_stack.push(self.active_page);
+                }
+                page
+            }
+            PageRequest::Pop => {
+                let page = self.page_stack.pop();
+                if page.is_none() {
+                    tracing::warn!("No page to return to");
+                }
+                page
+            }
+        };
+
+        if let Some(page) = page {
+            self.activate_page(page);
+        }
+    }
+
+    fn page_index(&self, name: &str) -> Option<usize> {
+        let index = self.pages.iter().position(|page| page.name == name);
+        if index.is_none() {
+            tracing::warn!(?name, "No such page");
+        }
+        index
+    }
+
+    fn activate_page(&mut self, page: usize) {
+        if page == self.active_page {
+            return;
+        }
+
+        for key_state in self.rows_mut().iter_mut().flat_map(|r| r.0.iter_mut()) {
+            if key_state.pressed {
+                key_state.leave_page();
+            }
+        }
+
+        // Keys that are still held belong to the gesture on the previous page
+        self.suppressed_keys
+            .extend(self.pressed_keys.iter().copied());
+
+        tracing::info!(page = self.pages[page].name, "Activating page");
+        self.active_page = page;
+    }
+}
+
+/// A keymap with the runtime state of its keys
+#[derive(Clone, Debug)]
+struct Page {
+    name: String,
+    rows: Vec<Row>,
+}
+
+impl Page {
+    fn from_keymap(
+        name: &str,
+        keymap: &crate::config::Keymap,
+        config: &crate::config::Config,
+        action_errors: &crate::action::ActionErrors,
+    ) -> Self {
+        let keypad = &config.keypad;
+        Self {
+            name: name.to_string(),
+            rows: (0..keypad.rows)
+                .map(|row| {
+                    Row((0..keypad.columns)
+                        .map(|column| {
+                            let pad: &PadConfig = keymap
+                                .pad(row, column)
+                                .expect("Config validation ensures that all pads are configured");
+                            let position = KeyPosition {
+                                index: row * keypad.columns + column,
+                                row,
+                                column,
+                            };
+                            let event_topic = config
+                                .mqtt_event_prefix
+                                .as_ref()
+                                .map(|prefix| crate::event::topic(prefix, position.index));
+
+                            let mut key_state = KeyState::new(pad, position, name, event_topic);
+                            key_state.action_errors = Some(action_errors.clone());
+                            key_state
+                        })
+                        .collect())
+                })
+                .collect(),
         }
     }
 }
 
+#[derive(Clone, Debug)]
+struct Row(Vec<KeyState>);
+
+/// A change of the active page, requested by an action
+#[derive(Clone, Debug)]
+pub(crate) enum PageRequest {
+    /// Replace the active page
+    Switch(String),
+    /// Activate a page, remembering the active page to return to it later
+    Push(String),
+    /// Return to the last remembered page
+    Pop,
+}
+
+/// Keys that trigger actions when they are held down together
+#[derive(Clone, Debug)]
+struct Chord {
+    keys: BTreeSet<u8>,
+    actions: Vec<crate::action::Action>,
+    suppress_key_actions: bool,
+}
+
+impl From<&crate::config::ChordConfig> for Chord {
+    fn from(config: &crate::config::ChordConfig) -> Self {
+        Self {
+            keys: config.keys.iter().copied().collect(),
+            actions: config
+                .actions
+                .iter()
+                .map(crate::action::Action::from)
+                .collect(),
+            suppress_key_actions: config.suppress_key_actions,
+        }
+    }
+}
+
+/// Where a key is located on the keypad
+#[derive(Clone, Copy, Debug)]
+struct KeyPosition {
+    index: u8,
+    row: u8,
+    column: u8,
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct KeyState {
+    position: KeyPosition,
+    /// The name of the page the key belongs to
+    page: String,
+    label: Option<String>,
+    /// The topic to publish events of the key on, if enabled
+    event_topic: Option<String>,
+
     color_pressed: crate::util::Rgb,
     color_released: crate::util::Rgb,
     color_alternative: crate::util::Rgb,
     pressed: bool,
+    pressed_at: Option<Instant>,
+    /// How long the key was held down the last time it was pressed
+    last_press_duration: Duration,
+    /// How often the key was pressed
+    press_count: u64,
     blinking: bool,
     blinking_alternative_color: bool,
     blink_state: BlinkState,
+    /// Colors set by control actions, replacing the configured colors until reset
+    color_overrides: ColorOverrides,
+    /// A color that is shown regardless of the state of the key, until the deadline
+    flash: Option<(crate::util::Rgb, Instant)>,
+
+    press_tracker: PressTracker,
+    /// Detects gestures for events, regardless of the actions that are bound to them
+    ///
+    /// Only set if events are enabled.
+    event_tracker: Option<PressTracker>,
+    page_request: Option<PageRequest>,
+    effect_request: Option<animation::EffectRequest>,
+    brightness_request: Option<crate::brightness::BrightnessRequest>,
+    effect: Option<animation::Effect>,
+
+    state_topic: Option<crate::state_topic::StateTopic>,
+    /// The last payload received on the state topic
+    state_payload: Option<Vec<u8>>,
+    state_colors: crate::state_topic::StateColors,
 
     on_press: Vec<crate::action::Action>,
+    on_long_press: Vec<crate::action::Action>,
+    /// Actions for multi presses, by the number of taps
+    on_multi_press: BTreeMap<u8, Vec<crate::action::Action>>,
     on_release: Vec<crate::action::Action>,
+    /// Where actions that run in the background report their errors
+    action_errors: Option<crate::action::ActionErrors>,
 }
 
-impl From<&PadConfig> for KeyState {
-    fn from(config: &PadConfig) -> Self {
+impl KeyState {
+    fn new(
+        config: &PadConfig,
+        position: KeyPosition,
+        page: &str,
+        event_topic: Option<String>,
+    ) -> Self {
+        let mut on_multi_press = BTreeMap::<u8, Vec<crate::action::Action>>::new();
+        let on_double_press = config.on_double_press.iter().flatten();
+        let on_double_press = on_double_press
+            .map(crate::action::Action::from)
+            .collect::<Vec<_>>();
+        if !on_double_press.is_empty() {
+            on_multi_press.insert(2, on_double_press);
+        }
+        for multi_press in config.on_multi_press.iter().flatten() {
+            on_multi_press
+                .entry(multi_press.count)
+                .or_default()
+                .extend(multi_press.actions.iter().map(crate::action::Action::from));
+        }
+
+        let event_tracker = event_topic.is_some().then(|| {
+            PressTracker::new(
+                Some(
+                    config
+                        .long_press_threshold
+                        .unwrap_or(crate::konst::DEFAULT_LONG_PRESS_THRESHOLD),
+                ),
+                Some(
+                    config
+                        .tap_window
+                        .unwrap_or(crate::konst::DEFAULT_TAP_WINDOW),
+                ),
+                u8::MAX,
+            )
+        });
+
         Self {
-            color_pressed: crate::util::Rgb::from([
-                config.pressed[0],
-                config.pressed[1],
-                config.pressed[2],
-            ]),
-            color_released: crate::util::Rgb::from([
-                config.released[0],
-                config.released[1],
-                config.released[2],
-            ]),
-            color_alternative: crate::util::Rgb::from([
-                config.alternative[0],
-                config.alternative[1],
-                config.alternative[2],
-            ]),
+            position,
+            page: page.to_string(),
+            label: config.label.clone(),
+            event_topic,
+
+            color_pressed: config.pressed.unwrap_or_default(),
+            color_released: config.released.unwrap_or_default(),
+            color_alternative: config.alternative.unwrap_or_default(),
             pressed: false,
+            pressed_at: None,
+            last_press_duration: Duration::ZERO,
+            press_count: 0,
             blinking: false,
             blinking_alternative_color: false,
             blink_state: BlinkState::Off,
+            color_overrides: ColorOverrides::default(),
+            flash: None,
+
+            page_request: None,
+            effect_request: None,
+            brightness_request: None,
+            effect: config.effect.as_ref().map(animation::Effect::from),
+
+            state_topic: config
+                .state_topic
+                .as_ref()
+                .map(crate::state_topic::StateTopic::from),
+            state_payload: None,
+            state_colors: crate::state_topic::StateColors::default(),
+
+            press_tracker: PressTracker::new(
+                config
+                    .on_long_press
+                    .iter()
+                    .flatten()
+                    .next()
+                    .is_some()
+                    .then(|| {
+                        config
+                            .long_press_threshold
+                            .unwrap_or(crate::konst::DEFAULT_LONG_PRESS_THRESHOLD)
+                    }),
+                (!on_multi_press.is_empty()).then(|| {
+                    config
+                        .tap_window
+                        .unwrap_or(crate::konst::DEFAULT_TAP_WINDOW)
+                }),
+                on_multi_press.keys().copied().max().unwrap_or_default(),
+            ),
+            event_tracker,
 
             on_press: config
                 .on_press
                 .iter()
+                .flatten()
+                .map(crate::action::Action::from)
+                .collect::<Vec<_>>(),
+
+            on_long_press: config
+                .on_long_press
+                .iter()
+                .flatten()
                 .map(crate::action::Action::from)
                 .collect::<Vec<_>>(),
 
+            on_multi_press,
+
             on_release: config
                 .on_release
                 .iter()
+                .flatten()
                 .map(crate::action::Action::from)
                 .collect::<Vec<_>>(),
+            action_errors: None,
         }
     }
 }
 
 impl KeyState {
-    async fn pressed(&mut self, mqtt: &CloudmqttClient) {
+    async fn pressed(&mut self, mqtt: &crate::mqtt::Client) {
+        let now = Instant::now();
+        self.mark_pressed(now);
+        self.publish_event(crate::event::KeyEventKind::Pressed, None, mqtt)
+            .await;
+
+        let event_triggers = self.event_tracker.as_mut().map(|t| t.pressed(now));
+        self.publish_gesture_events(event_triggers.unwrap_or_default(), mqtt)
+            .await;
+        let triggers = self.press_tracker.pressed(now);
+        self.run_triggers(triggers, mqtt).await
+    }
+
+    async fn released(&mut self, mqtt: &crate::mqtt::Client) {
+        let now = Instant::now();
+        self.mark_released(now);
+        self.publish_event(crate::event::KeyEventKind::Released, None, mqtt)
+            .await;
+
+        let event_triggers = self.event_tracker.as_mut().map(|t| t.released(now));
+        self.publish_gesture_events(event_triggers.unwrap_or_default(), mqtt)
+            .await;
+        let triggers = self.press_tracker.released(now);
+        self.run_triggers(triggers, mqtt).await
+    }
+
+    /// Press the key without detecting gestures or running any actions, only publishing the event
+    async fn pressed_silently(&mut self, mqtt: &crate::mqtt::Client) {
+        self.mark_pressed(Instant::now());
+        self.cancel_gestures();
+        self.publish_event(crate::event::KeyEventKind::Pressed, None, mqtt)
+            .await;
+    }
+
+    /// Release the key without detecting gestures or running any actions, only publishing the
+    /// event
+    async fn released_silently(&mut self, mqtt: &crate::mqtt::Client) {
+        self.mark_released(Instant::now());
+        self.cancel_gestures();
+        self.publish_event(crate::event::KeyEventKind::Released, None, mqtt)
+            .await;
+    }
+
+    /// Forget that the key is held down, when its page is left
+    ///
+    /// No event is published, as the key is still held down and its release is published once it
+    /// is released on the new page.
+    fn leave_page(&mut self) {
+        self.mark_released(Instant::now());
+        self.cancel_gestures();
+    }
+
+    /// Forget the gestures that are currently in progress, without triggering anything
+    fn cancel_gestures(&mut self) {
+        self.press_tracker.cancel();
+        if let Some(event_tracker) = self.event_tracker.as_mut() {
+            event_tracker.cancel();
+        }
+    }
+
+    fn mark_pressed(&mut self, now: Instant) {
         self.pressed = true;
+        self.pressed_at = Some(now);
+        self.press_count = self.press_count.wrapping_add(1);
+    }
 
-        for action in self.on_press.clone().iter() {
-            if let Err(error) = action.execute(self, mqtt).await {
-                tracing::error!(?error, ?action, "Executing action yielded error");
+    fn mark_released(&mut self, now: Instant) {
+        self.pressed = false;
+        if let Some(pressed_at) = self.pressed_at.take() {
+            self.last_press_duration = now.duration_since(pressed_at);
+        }
+    }
+
+    /// The variables that can be used in templates of actions of this key
+    pub(crate) fn template_variables(&self) -> BTreeMap<&'static str, String> {
+        let duration = match self.pressed_at {
+            Some(pressed_at) => pressed_at.elapsed(),
+            None => self.last_press_duration,
+        };
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        BTreeMap::from([
+            ("index", self.position.index.to_string()),
+            ("row", self.position.row.to_string()),
+            ("col", self.position.column.to_string()),
+            ("duration_ms", duration.as_millis().to_string()),
+            ("timestamp", timestamp.as_millis().to_string()),
+            ("page", self.page.to_string()),
+            ("blinking", self.blinking.to_string()),
+            ("counter", self.press_count.to_string()),
+        ])
+    }
+
+    /// The next point in time at which the key needs to be polled
+    fn deadline(&self) -> Option<Instant> {
+        let flash_ends_at = self.flash.map(|(_, until)| until);
+        let event_deadline = self.event_tracker.as_ref().and_then(PressTracker::deadline);
+        [self.press_tracker.deadline(), event_deadline, flash_ends_at]
+            .into_iter()
+            .flatten()
+            .min()
+    }
+
+    async fn poll_timers(&mut self, now: Instant, mqtt: &crate::mqtt::Client) {
+        if self.flash.is_some_and(|(_, until)| until <= now) {
+            tracing::trace!("Flash ended");
+            self.flash = None;
+        }
+
+        let event_triggers = self.event_tracker.as_mut().map(|t| t.poll(now));
+        self.publish_gesture_events(event_triggers.unwrap_or_default(), mqtt)
+            .await;
+        let triggers = self.press_tracker.poll(now);
+        self.run_triggers(triggers, mqtt).await
+    }
+
+    /// Publish the events of the gestures detected by the event tracker
+    ///
+    /// Presses and releases are published as soon as they happen instead.
+    async fn publish_gesture_events(&self, triggers: Vec<Trigger>, mqtt: &crate::mqtt::Client) {
+        for trigger in triggers {
+            match trigger {
+                Trigger::LongPress => {
+                    self.publish_event(crate::event::KeyEventKind::LongPress, None, mqtt)
+                        .await
+                }
+                Trigger::MultiPress(2) => {
+                    self.publish_event(crate::event::KeyEventKind::DoublePress, None, mqtt)
+                        .await
+                }
+                Trigger::MultiPress(taps) => {
+                    self.publish_event(crate::event::KeyEventKind::MultiPress, Some(taps), mqtt)
+                        .await
+                }
+                Trigger::Press | Trigger::Release => {}
             }
         }
     }
 
-    async fn released(&mut self, mqtt: &CloudmqttClient) {
-        self.pressed = false;
+    async fn run_triggers(&mut self, triggers: Vec<Trigger>, mqtt: &crate::mqtt::Client) {
+        for trigger in triggers {
+            tracing::debug!(?trigger, "Running actions");
+            let actions = match trigger {
+                Trigger::Press => self.on_press.clone(),
+                Trigger::LongPress => self.on_long_press.clone(),
+                Trigger::MultiPress(taps) => {
+                    self.on_multi_press.get(&taps).cloned().unwrap_or_default()
+                }
+                Trigger::Release => self.on_release.clone(),
+            };
+
+            self.run_actions(&actions, mqtt).await;
+        }
+    }
 
-        for action in self.on_release.clone().iter() {
+    fn report(&self) -> crate::report::KeyReport<'_> {
+        crate::report::KeyReport {
+            index: self.position.index,
+            row: self.position.row,
+            col: self.position.column,
+            label: self.label.as_deref(),
+            page: &self.page,
+            pressed: self.pressed,
+            blinking: self.blinking,
+            alternative_color: self.blinking_alternative_color,
+            colors: crate::report::ColorsReport {
+                released: self.base_color_released(),
+                pressed: self.base_color_pressed(),
+                alternative: self.color_alternative(),
+            },
+        }
+    }
+
+    /// Publish an event of the key, if events are enabled
+    async fn publish_event(
+        &self,
+        event: crate::event::KeyEventKind,
+        taps: Option<u8>,
+        mqtt: &crate::mqtt::Client,
+    ) {
+        let Some(topic) = self.event_topic.as_ref() else {
+            return;
+        };
+
+        let event = crate::event::KeyEvent {
+            event,
+            row: self.position.row,
+            col: self.position.column,
+            label: self.label.as_deref(),
+            page: &self.page,
+            taps,
+        };
+
+        match serde_json::to_vec(&event) {
+            Ok(payload) => {
+                tracing::debug!(topic, ?event, "Publishing key event");
+                let qos = crate::mqtt::Qos::AtLeast;
+                crate::mqtt::publish(mqtt, topic.to_string(), payload, qos, false).await
+            }
+            Err(error) => tracing::error!(?error, ?event, "Failed to serialize key event"),
+        }
+    }
+
+    async fn run_actions(&mut self, actions: &[crate::action::Action], mqtt: &crate::mqtt::Client) {
+        for action in actions.iter() {
             if let Err(error) = action.execute(self, mqtt).await {
                 tracing::error!(?error, ?action, "Executing action yielded error");
             }
         }
     }
 
+    fn adopt_runtime_state(&mut self, previous: &KeyState) {
+        self.blinking = previous.blinking;
+        self.blinking_alternative_color = previous.blinking_alternative_color;
+        self.blink_state = previous.blink_state;
+        self.color_overrides = previous.color_overrides;
+        self.flash = previous.flash;
+
+        let topic = |key_state: &KeyState| key_state.state_topic.as_ref().map(|s| s.topic.clone());
+        let same_topic = topic(self) == topic(previous);
+        if let Some(payload) = previous.state_payload.as_ref().filter(|_| same_topic) {
+            self.apply_state(payload);
+        }
+    }
+
+    fn apply_state(&mut self, payload: &[u8]) {
+        let Some(state_topic) = self.state_topic.as_ref() else {
+            return;
+        };
+
+        self.state_colors = state_topic.colors(payload);
+        tracing::trace!(topic = state_topic.topic, state_colors = ?self.state_colors, "Applied state");
+        self.state_payload = Some(payload.to_vec());
+    }
+
+    /// Where to report errors of actions that finish in the background, if anywhere
+    pub(crate) fn action_errors(&self) -> Option<crate::action::ActionErrors> {
+        self.action_errors.clone()
+    }
+
+    pub(crate) fn request_page(&mut self, request: PageRequest) {
+        self.page_request = Some(request);
+    }
+
+    pub(crate) fn request_effect(&mut self, request: animation::EffectRequest) {
+        self.effect_request = Some(request);
+    }
+
+    pub(crate) fn request_brightness(&mut self, request: crate::brightness::BrightnessRequest) {
+        self.brightness_request = Some(request);
+    }
+
+    /// Apply the effect of the key to its color, unless it is flashing
+    fn animate(&self, color: crate::util::Rgb, elapsed: Duration) -> crate::util::Rgb {
+        match self.effect.as_ref() {
+            Some(effect) if self.flash.is_none() => effect.apply(color, elapsed),
+            _ => color,
+        }
+    }
+
     pub(crate) fn toggle_blinking(&mut self) {
         tracing::trace!(blinking = ?!self.blinking, "Set blinking");
         self.blinking = !self.blinking;
@@ -261,40 +1088,88 @@ impl KeyState {
         self.blinking_alternative_color = !self.blinking_alternative_color;
     }
 
-    fn color_pressed(&mut self) -> crate::util::Rgb {
-        if self.blinking && self.pressed {
+    fn color_pressed(&self) -> crate::util::Rgb {
+        if let Some((color, _)) = self.flash {
+            color
+        } else if self.blinking && self.pressed {
             tracing::trace!(blinking = self.blinking, "Color::Pressed");
             self.color_blinking()
         } else {
-            self.color_pressed
+            self.base_color_pressed()
         }
     }
 
-    fn color_released(&mut self) -> crate::util::Rgb {
-        if self.blinking && !self.pressed {
+    fn color_released(&self) -> crate::util::Rgb {
+        if let Some((color, _)) = self.flash {
+            color
+        } else if self.blinking && !self.pressed {
             tracing::trace!(blinking = self.blinking, "Color::Released");
             self.color_blinking()
         } else {
-            self.color_released
+            self.base_color_released()
         }
     }
 
-    fn color_blinking(&mut self) -> crate::util::Rgb {
+    /// The color of the pressed key without any effects
+    ///
+    /// Colors set by control actions take precedence over the state topic, which takes
+    /// precedence over the configured color.
+    fn base_color_pressed(&self) -> crate::util::Rgb {
+        self.color_overrides
+            .pressed
+            .or(self.state_colors.pressed)
+            .unwrap_or(self.color_pressed)
+    }
+
+    /// The color of the released key without any effects
+    ///
+    /// Colors set by control actions take precedence over the state topic, which takes
+    /// precedence over the configured color.
+    fn base_color_released(&self) -> crate::util::Rgb {
+        self.color_overrides
+            .released
+            .or(self.state_colors.released)
+            .unwrap_or(self.color_released)
+    }
+
+    fn color_alternative(&self) -> crate::util::Rgb {
+        self.color_overrides
+            .alternative
+            .unwrap_or(self.color_alternative)
+    }
+
+    fn color_blinking(&self) -> crate::util::Rgb {
         tracing::trace!(blink_state = ?self.blink_state);
         match self.blink_state {
             BlinkState::On => {
-                self.blink_state = BlinkState::Off;
                 if self.blinking_alternative_color {
-                    self.color_alternative
+                    self.color_alternative()
                 } else {
-                    self.color_pressed
+                    self.base_color_pressed()
                 }
             }
-            BlinkState::Off => {
-                self.blink_state = BlinkState::On;
-                self.color_released
-            }
+            BlinkState::Off => self.base_color_released(),
+        }
+    }
+
+    fn advance_blinking(&mut self) {
+        if !self.blinking {
+            return;
         }
+
+        self.blink_state = match self.blink_state {
+            BlinkState::On => BlinkState::Off,
+            BlinkState::Off => BlinkState::On,
+        };
+    }
+
+    /// Drop all changes made by control actions and show the configured colors again
+    fn reset_to_config(&mut self) {
+        self.blinking = false;
+        self.blinking_alternative_color = false;
+        self.blink_state = BlinkState::Off;
+        self.color_overrides = ColorOverrides::default();
+        self.flash = None;
     }
 
     fn run_ctrl_action_on_key(&mut self, action: crate::action::ControlAction) {
@@ -308,12 +1183,687 @@ impl KeyState {
                 self.blinking = !self.blinking;
                 self.blinking_alternative_color = !self.blinking_alternative_color;
             }
+            crate::action::ControlAction::SetBlinking(blinking) => {
+                tracing::trace!(?blinking, "Set blinking");
+                self.blinking = blinking;
+                self.blinking_alternative_color = false;
+            }
+            crate::action::ControlAction::SetBlinkingAlternativeColor(blinking) => {
+                tracing::trace!(?blinking, "Set blinking with alternative color");
+                self.blinking = blinking;
+                self.blinking_alternative_color = blinking;
+            }
+            crate::action::ControlAction::SetColor {
+                released,
+                pressed,
+                alternative,
+            } => {
+                tracing::trace!(?released, ?pressed, ?alternative, "Set color");
+                let overrides = &mut self.color_overrides;
+                overrides.released = released.or(overrides.released);
+                overrides.pressed = pressed.or(overrides.pressed);
+                overrides.alternative = alternative.or(overrides.alternative);
+            }
+            crate::action::ControlAction::ResetToConfig => {
+                tracing::trace!("Reset to config");
+                self.reset_to_config();
+            }
+            crate::action::ControlAction::Flash { color, duration } => {
+                tracing::trace!(?color, ?duration, "Flash");
+                self.flash = Some((color, Instant::now() + duration));
+            }
+            // Page and brightness changes apply to the keypad, see `KeypadState::run_ctrl_action`
+            crate::action::ControlAction::SwitchPage { .. }
+            | crate::action::ControlAction::PushPage { .. }
+            | crate::action::ControlAction::PopPage
+            | crate::action::ControlAction::SetBrightness(_)
+            | crate::action::ControlAction::BrightnessUp
+            | crate::action::ControlAction::BrightnessDown => {}
         }
     }
 }
 
+/// Colors of a key that were set by control actions
+#[derive(Clone, Copy, Debug, Default)]
+struct ColorOverrides {
+    released: Option<crate::util::Rgb>,
+    pressed: Option<crate::util::Rgb>,
+    alternative: Option<crate::util::Rgb>,
+}
+
 #[derive(Clone, Copy, Debug)]
 enum BlinkState {
     On,
     Off,
 }
+
+/// A gesture that was detected on a key, mapped to the list of actions that are executed for it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Trigger {
+    Press,
+    LongPress,
+    /// The key was tapped the given number of times, always at least two
+    MultiPress(u8),
+    Release,
+}
+
+impl Trigger {
+    fn from_taps(taps: u8) -> Self {
+        if taps > 1 {
+            Trigger::MultiPress(taps)
+        } else {
+            Trigger::Press
+        }
+    }
+}
+
+/// Detects gestures from the timing of presses and releases of a single key
+#[derive(Clone, Debug)]
+struct PressTracker {
+    /// Only set if the key has long press actions, so that presses are not deferred needlessly
+    long_press_threshold: Option<Duration>,
+    /// Only set if the key has multi press actions, so that presses are not deferred needlessly
+    tap_window: Option<Duration>,
+    /// The highest number of taps that actions are bound to
+    ///
+    /// Reaching it fires immediately, as there is nothing left to wait for.
+    max_taps: u8,
+
+    pressed_at: Option<Instant>,
+    long_press_fired: bool,
+    taps: u8,
+    tap_window_closes_at: Option<Instant>,
+}
+
+impl PressTracker {
+    fn new(
+        long_press_threshold: Option<Duration>,
+        tap_window: Option<Duration>,
+        max_taps: u8,
+    ) -> Self {
+        Self {
+            long_press_threshold,
+            tap_window,
+            max_taps,
+            pressed_at: None,
+            long_press_fired: false,
+            taps: 0,
+            tap_window_closes_at: None,
+        }
+    }
+
+    fn pressed(&mut self, now: Instant) -> Vec<Trigger> {
+        self.pressed_at = Some(now);
+        self.long_press_fired = false;
+
+        let mut triggers = Vec::new();
+        if self
+            .tap_window_closes_at
+            .is_some_and(|closes_at| closes_at <= now)
+        {
+            // The window closed before it was polled, so this press starts a new series
+            triggers.push(Trigger::from_taps(std::mem::take(&mut self.taps)));
+        }
+
+        if self.tap_window.is_some() {
+            self.taps = self.taps.saturating_add(1);
+            self.tap_window_closes_at = None;
+        }
+
+        // Otherwise, which gesture this is is only known on release or timeout
+        if self.long_press_threshold.is_none() && self.tap_window.is_none() {
+            triggers.push(Trigger::Press);
+        }
+        triggers
+    }
+
+    fn released(&mut self, now: Instant) -> Vec<Trigger> {
+        let pressed_at = self.pressed_at.take();
+        let held_long = matches!(
+            (self.long_press_threshold, pressed_at),
+            (Some(threshold), Some(pressed_at)) if now.duration_since(pressed_at) >= threshold
+        );
+
+        let mut triggers = Vec::with_capacity(2);
+        if self.long_press_fired {
+            self.taps = 0;
+        } else if held_long {
+            self.taps = 0;
+            triggers.push(Trigger::LongPress);
+        } else if let Some(tap_window) = self.tap_window {
+            if self.taps >= self.max_taps {
+                triggers.push(Trigger::from_taps(std::mem::take(&mut self.taps)));
+            } else {
+                self.tap_window_closes_at = Some(now + tap_window);
+            }
+        } else if self.long_press_threshold.is_some() {
+            triggers.push(Trigger::Press);
+        }
+        triggers.push(Trigger::Release);
+        triggers
+    }
+
+    fn poll(&mut self, now: Instant) -> Vec<Trigger> {
+        if self
+            .long_press_deadline()
+            .is_some_and(|deadline| deadline <= now)
+        {
+            self.long_press_fired = true;
+            self.taps = 0;
+            return vec![Trigger::LongPress];
+        }
+
+        match self.tap_window_closes_at {
+            Some(deadline) if deadline <= now => {
+                self.tap_window_closes_at = None;
+                vec![Trigger::from_taps(std::mem::take(&mut self.taps))]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Forget the gesture that is currently in progress, without triggering anything
+    fn cancel(&mut self) {
+        self.pressed_at = None;
+        self.long_press_fired = false;
+        self.taps = 0;
+        self.tap_window_closes_at = None;
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        match (self.long_press_deadline(), self.tap_window_closes_at) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+
+    fn long_press_deadline(&self) -> Option<Instant> {
+        if self.long_press_fired {
+            return None;
+        }
+
+        Some(self.pressed_at? + self.long_press_threshold?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::KeyPosition;
+    use super::KeyState;
+    use super::KeypadState;
+    use super::PageRequest;
+    use super::PressTracker;
+    use super::Trigger;
+
+    const HEADER: &str = r#"
+        mqtt_broker_addr = "localhost"
+        mqtt_broker_port = 1883
+        mqtt_subscribe_prefix = "keypad"
+        mqtt_control_prefix = "keypad/control"
+        interval_duration = "1s"
+    "#;
+
+    fn config(config_str: &str) -> crate::config::Config {
+        let config = crate::config::Config::parse(&format!("{HEADER}{config_str}")).unwrap();
+        config.validate().unwrap();
+        config
+    }
+
+    fn keypad_state(config_str: &str) -> KeypadState {
+        let (action_errors, _) = tokio::sync::mpsc::unbounded_channel();
+        KeypadState::from_config(&config(config_str), &action_errors)
+    }
+
+    /// The name of the active page and the names of the pages on the stack
+    fn pages(state: &KeypadState) -> (&str, Vec<&str>) {
+        let name = |index: &usize| state.pages[*index].name.as_str();
+        let stack = state.page_stack.iter().map(name).collect();
+        (name(&state.active_page), stack)
+    }
+
+    const PAGES: &str = r#"
+        [keypad]
+        rows = 1
+        columns = 2
+        [keypad.defaults]
+
+        [pages.lights]
+        [pages.media]
+    "#;
+
+    #[test]
+    fn test_page_requests() {
+        let mut state = keypad_state(PAGES);
+        assert_eq!(pages(&state), ("default", vec![]));
+
+        state.apply_page_request(PageRequest::Push(String::from("lights")));
+        state.apply_page_request(PageRequest::Push(String::from("media")));
+        assert_eq!(pages(&state), ("media", vec!["default", "lights"]));
+
+        // Switching replaces the active page, but keeps the pages to return to
+        state.apply_page_request(PageRequest::Switch(String::from("default")));
+        assert_eq!(pages(&state), ("default", vec!["default", "lights"]));
+
+        state.apply_page_request(PageRequest::Pop);
+        assert_eq!(pages(&state), ("lights", vec!["default"]));
+        state.apply_page_request(PageRequest::Pop);
+        assert_eq!(pages(&state), ("default", vec![]));
+
+        // Popping the empty stack stays on the active page
+        state.apply_page_request(PageRequest::Switch(String::from("media")));
+        state.apply_page_request(PageRequest::Pop);
+        assert_eq!(pages(&state), ("media", vec![]));
+
+        // Unknown pages are neither switched to nor pushed
+        state.apply_page_request(PageRequest::Switch(String::from("unknown")));
+        state.apply_page_request(PageRequest::Push(String::from("unknown")));
+        assert_eq!(pages(&state), ("media", vec![]));
+    }
+
+    #[test]
+    fn test_page_fallback_on_reload() {
+        let mut previous = keypad_state(PAGES);
+        previous.apply_page_request(PageRequest::Push(String::from("lights")));
+        previous.apply_page_request(PageRequest::Push(String::from("media")));
+
+        let mut state = keypad_state(&PAGES.replace("[pages.lights]", ""));
+        state.adopt_runtime_state(&previous);
+        assert_eq!(pages(&state), ("media", vec!["default"]));
+
+        // Without the active page, the default page is active
+        let mut state = keypad_state(&PAGES.replace("[pages.media]", ""));
+        state.adopt_runtime_state(&previous);
+        assert_eq!(pages(&state), ("default", vec!["default", "lights"]));
+    }
+
+    #[test]
+    fn test_adopt_runtime_state() {
+        let config_str = r#"
+        [keypad]
+        rows = 1
+        columns = 3
+        [keypad.defaults]
+        released = [1, 1, 1]
+
+        [pages.media]
+        "#;
+        let mut previous = keypad_state(config_str);
+        let set_color = serde_json::from_str(r#"{"SetColor": {"released": [5, 5, 5]}}"#).unwrap();
+        let blink = crate::action::ControlAction::SetBlinking(true);
+        previous.run_ctrl_action(&crate::action::ControlTarget::Key(0), set_color);
+        previous.run_ctrl_action(&crate::action::ControlTarget::Key(1), blink.clone());
+        previous.run_ctrl_action(&crate::action::ControlTarget::Key(2), blink);
+        previous.set_brightness(40);
+        let effect: crate::config::PadEffectConfig =
+            serde_json::from_str(r#"{"Ripple": {"color": [9, 9, 9], "duration": "1s"}}"#).unwrap();
+        let effect = super::animation::PadEffect::from(&effect);
+        previous
+            .key_mut(0)
+            .unwrap()
+            .request_effect(super::animation::EffectRequest::Start(effect));
+        previous.apply_requests();
+        previous.apply_page_request(PageRequest::Push(String::from("media")));
+
+        // The active page is removed and the grid changes from 1x3 to 2x2
+        let config_str = config_str
+            .replace("rows = 1", "rows = 2")
+            .replace("columns = 3", "columns = 2")
+            .replace("[pages.media]", "");
+        let mut state = keypad_state(&config_str);
+        state.adopt_runtime_state(&previous);
+        assert_eq!(pages(&state), ("default", vec!["default"]));
+        assert_eq!(state.brightness.level(), 40);
+        assert!(state.pad_effect.is_some());
+
+        // Keys keep their state by their position on the page
+        let key_0 = state.key_mut(0).unwrap();
+        assert_eq!(key_0.color_released().as_slice(), [5, 5, 5]);
+        assert!(!key_0.blinking);
+        assert!(state.key_mut(1).unwrap().blinking);
+        let key_2 = state.key_mut(2).unwrap();
+        assert_eq!((key_2.position.row, key_2.position.column), (1, 0));
+        assert!(!key_2.blinking);
+        assert_eq!(key_2.color_released().as_slice(), [1, 1, 1]);
+    }
+
+    #[test]
+    fn test_chords() {
+        let config_str = r#"
+        [keypad]
+        rows = 1
+        columns = 3
+        [keypad.defaults]
+
+        [[chords]]
+        keys = [0, 1]
+        actions = ["ToggleBlinking"]
+        suppress_key_actions = true
+
+        [[chords]]
+        keys = [0, 1, 2]
+        actions = ["PopPage"]
+        "#;
+        let mut state = keypad_state(config_str);
+        let chord_keys = |chord: Option<super::Chord>| chord.map(|chord| chord.keys);
+
+        assert!(state.hold_key(1).is_none());
+        let chord = state.hold_key(0).unwrap();
+        assert_eq!(chord.keys, [0, 1].into());
+        assert!(
+            chord
+                .actions
+                .iter()
+                .all(|action| matches!(action, crate::action::Action::ToggleBlinking))
+        );
+
+        // Keys of a chord that suppresses key actions are suppressed until they are released
+        state.suppress_chord_keys(&chord);
+        assert_eq!(state.suppressed_keys, [0, 1].into());
+
+        // The chord with the most keys wins
+        assert_eq!(chord_keys(state.hold_key(2)), Some([0, 1, 2].into()));
+        let chord = state.hold_key(2).unwrap();
+        state.suppress_chord_keys(&chord);
+        assert_eq!(state.suppressed_keys, [0, 1].into());
+
+        // Releasing the keys in any order ends the suppression of each key on its own
+        assert!(!state.release_key(2));
+        assert!(state.release_key(0));
+        assert_eq!(state.suppressed_keys, [1].into());
+        // Holding the key again while the other key is still held completes the chord again
+        assert_eq!(chord_keys(state.hold_key(0)), Some([0, 1].into()));
+        assert!(!state.release_key(0));
+        assert!(state.release_key(1));
+        assert!(state.pressed_keys.is_empty());
+        assert!(state.suppressed_keys.is_empty());
+
+        // Chords only complete while all of their keys are held
+        assert!(state.hold_key(0).is_none());
+        assert!(!state.release_key(0));
+        assert!(state.hold_key(1).is_none());
+    }
+
+    #[test]
+    fn test_press_without_long_press() {
+        let mut tracker = PressTracker::new(None, None, 0);
+        let now = Instant::now();
+
+        assert_eq!(tracker.pressed(now), vec![Trigger::Press]);
+        assert_eq!(tracker.deadline(), None);
+        assert_eq!(
+            tracker.released(now + Duration::from_secs(5)),
+            vec![Trigger::Release]
+        );
+    }
+
+    #[test]
+    fn test_long_press_on_release() {
+        let mut tracker = PressTracker::new(Some(Duration::from_millis(500)), None, 0);
+        let now = Instant::now();
+
+        assert_eq!(tracker.pressed(now), vec![]);
+        assert_eq!(
+            tracker.released(now + Duration::from_millis(100)),
+            vec![Trigger::Press, Trigger::Release]
+        );
+
+        assert_eq!(tracker.pressed(now), vec![]);
+        assert_eq!(
+            tracker.released(now + Duration::from_millis(600)),
+            vec![Trigger::LongPress, Trigger::Release]
+        );
+    }
+
+    #[test]
+    fn test_long_press_while_held() {
+        let mut tracker = PressTracker::new(Some(Duration::from_millis(500)), None, 0);
+        let now = Instant::now();
+
+        assert_eq!(tracker.pressed(now), vec![]);
+        assert_eq!(tracker.deadline(), Some(now + Duration::from_millis(500)));
+        assert_eq!(tracker.poll(now + Duration::from_millis(100)), vec![]);
+        assert_eq!(
+            tracker.poll(now + Duration::from_millis(500)),
+            vec![Trigger::LongPress]
+        );
+        assert_eq!(tracker.deadline(), None);
+        assert_eq!(
+            tracker.released(now + Duration::from_millis(900)),
+            vec![Trigger::Release]
+        );
+    }
+
+    #[test]
+    fn test_single_press_deferred_by_tap_window() {
+        let mut tracker = PressTracker::new(None, Some(Duration::from_millis(300)), 2);
+        let now = Instant::now();
+
+        assert_eq!(tracker.pressed(now), vec![]);
+        assert_eq!(
+            tracker.released(now + Duration::from_millis(50)),
+            vec![Trigger::Release]
+        );
+        assert_eq!(tracker.deadline(), Some(now + Duration::from_millis(350)));
+        assert_eq!(tracker.poll(now + Duration::from_millis(100)), vec![]);
+        assert_eq!(
+            tracker.poll(now + Duration::from_millis(350)),
+            vec![Trigger::Press]
+        );
+        assert_eq!(tracker.deadline(), None);
+    }
+
+    #[test]
+    fn test_multi_press() {
+        let mut tracker = PressTracker::new(None, Some(Duration::from_millis(300)), 3);
+        let now = Instant::now();
+
+        assert_eq!(tracker.pressed(now), vec![]);
+        assert_eq!(
+            tracker.released(now + Duration::from_millis(50)),
+            vec![Trigger::Release]
+        );
+        assert_eq!(tracker.pressed(now + Duration::from_millis(200)), vec![]);
+        assert_eq!(
+            tracker.released(now + Duration::from_millis(250)),
+            vec![Trigger::Release]
+        );
+        assert_eq!(
+            tracker.poll(now + Duration::from_millis(550)),
+            vec![Trigger::MultiPress(2)]
+        );
+
+        // Reaching the highest bound number of taps does not wait for the window to close
+        for i in 0..2 {
+            let t = now + Duration::from_secs(1) + Duration::from_millis(100 * i);
+            assert_eq!(tracker.pressed(t), vec![]);
+            assert_eq!(tracker.released(t), vec![Trigger::Release]);
+        }
+        let t = now + Duration::from_millis(1200);
+        assert_eq!(tracker.pressed(t), vec![]);
+        assert_eq!(
+            tracker.released(t),
+            vec![Trigger::MultiPress(3), Trigger::Release]
+        );
+        assert_eq!(tracker.deadline(), None);
+
+        // A press after the window closed starts a new series, even if it was not polled yet
+        let t = now + Duration::from_secs(2);
+        assert_eq!(tracker.pressed(t), vec![]);
+        assert_eq!(tracker.released(t), vec![Trigger::Release]);
+        let t = now + Duration::from_secs(3);
+        assert_eq!(tracker.pressed(t), vec![Trigger::Press]);
+        assert_eq!(tracker.released(t), vec![Trigger::Release]);
+        assert_eq!(
+            tracker.poll(t + Duration::from_millis(300)),
+            vec![Trigger::Press]
+        );
+    }
+
+    fn key_state() -> KeyState {
+        let config: crate::config::PadConfig = toml::from_str(
+            r#"
+            released = [0, 0, 0]
+            pressed = [1, 1, 1]
+            alternative = [2, 2, 2]
+            on_press = []
+            on_release = []
+            label = "Kitchen"
+            "#,
+        )
+        .unwrap();
+        let position = KeyPosition {
+            index: 7,
+            row: 1,
+            column: 2,
+        };
+        KeyState::new(&config, position, crate::konst::DEFAULT_PAGE, None)
+    }
+
+    #[test]
+    fn test_event_gestures_without_actions() {
+        assert!(key_state().event_tracker.is_none());
+
+        let config = crate::config::PadConfig::default();
+        let position = KeyPosition {
+            index: 0,
+            row: 0,
+            column: 0,
+        };
+        let topic = Some(String::from("keypad/events/key/0"));
+        let mut key_state = KeyState::new(&config, position, crate::konst::DEFAULT_PAGE, topic);
+        let now = Instant::now();
+
+        // Presses run immediately, as there are no gestures bound to actions
+        assert_eq!(key_state.press_tracker.pressed(now), vec![Trigger::Press]);
+
+        // Long presses are still detected for events
+        let event_tracker = key_state.event_tracker.as_mut().unwrap();
+        assert_eq!(event_tracker.pressed(now), vec![]);
+        let long_press_at = now + crate::konst::DEFAULT_LONG_PRESS_THRESHOLD;
+        assert_eq!(key_state.deadline(), Some(long_press_at));
+
+        let event_tracker = key_state.event_tracker.as_mut().unwrap();
+        assert_eq!(event_tracker.poll(long_press_at), vec![Trigger::LongPress]);
+
+        // And so are multi presses
+        let t = long_press_at + Duration::from_secs(1);
+        assert_eq!(event_tracker.released(t), vec![Trigger::Release]);
+        for _ in 0..2 {
+            assert_eq!(event_tracker.pressed(t), vec![]);
+            assert_eq!(event_tracker.released(t), vec![Trigger::Release]);
+        }
+        let closes_at = t + crate::konst::DEFAULT_TAP_WINDOW;
+        assert_eq!(event_tracker.poll(closes_at), vec![Trigger::MultiPress(2)]);
+
+        key_state.cancel_gestures();
+        assert_eq!(key_state.deadline(), None);
+    }
+
+    #[test]
+    fn test_ctrl_actions_set_and_reset_colors() {
+        let mut key_state = key_state();
+
+        let actions: Vec<crate::action::ControlAction> = serde_json::from_str(
+            r#"[
+                {"SetColor": {"released": [10, 10, 10]}},
+                {"SetBlinkingAlternativeColor": true},
+                {"SetBlinkingAlternativeColor": true}
+            ]"#,
+        )
+        .unwrap();
+        for action in actions {
+            key_state.run_ctrl_action_on_key(action);
+        }
+        assert_eq!(key_state.color_released().as_slice(), [10, 10, 10]);
+        key_state.advance_blinking();
+        assert_eq!(key_state.color_released().as_slice(), [2, 2, 2]);
+        assert_eq!(key_state.color_pressed().as_slice(), [1, 1, 1]);
+
+        let flash = serde_json::from_str(r#"{"Flash": {"color": [9, 9, 9], "duration": "1s"}}"#);
+        key_state.run_ctrl_action_on_key(flash.unwrap());
+        assert_eq!(key_state.color_released().as_slice(), [9, 9, 9]);
+        assert!(key_state.deadline().is_some());
+
+        key_state.run_ctrl_action_on_key(crate::action::ControlAction::ResetToConfig);
+        assert_eq!(key_state.color_released().as_slice(), [0, 0, 0]);
+        assert_eq!(key_state.deadline(), None);
+    }
+
+    #[test]
+    fn test_key_report() {
+        let mut key_state = key_state();
+        key_state.run_ctrl_action_on_key(crate::action::ControlAction::SetBlinking(true));
+
+        assert_eq!(
+            serde_json::to_value(key_state.report()).unwrap(),
+            serde_json::json!({
+                "index": 7,
+                "row": 1,
+                "col": 2,
+                "label": "Kitchen",
+                "page": "default",
+                "pressed": false,
+                "blinking": true,
+                "alternative_color": false,
+                "colors": {
+                    "released": [0, 0, 0],
+                    "pressed": [1, 1, 1],
+                    "alternative": [2, 2, 2],
+                },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_retained_frames() {
+        let broker = crate::mqtt::test_broker::TestBroker::bind().await;
+        let (mut connection, client) = broker.connect().await;
+        let config = config(PAGES);
+        let mut state =
+            KeypadState::from_config(&config, &tokio::sync::mpsc::unbounded_channel().0);
+
+        state.publish(&client, &config).await;
+        let mut frames = Vec::new();
+        for _ in 0..2 {
+            let publish = connection.read_publish().await;
+            frames.push((publish.topic, publish.retain));
+        }
+        frames.sort();
+        assert_eq!(
+            frames,
+            [
+                (String::from("keypad/arr/pressed"), true),
+                (String::from("keypad/arr/released"), true),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_retained_state() {
+        let broker = crate::mqtt::test_broker::TestBroker::bind().await;
+        let (mut connection, client) = broker.connect().await;
+        let config = config(&format!("mqtt_state_prefix = \"keypad\"\n{PAGES}"));
+        let mut state =
+            KeypadState::from_config(&config, &tokio::sync::mpsc::unbounded_channel().0);
+
+        state.publish_state(&client, &config).await;
+        let mut states = Vec::new();
+        for _ in 0..3 {
+            let publish = connection.read_publish().await;
+            states.push((publish.topic, publish.retain));
+        }
+        states.sort();
+        assert_eq!(
+            states,
+            [
+                (String::from("keypad/state/key/0"), true),
+                (String::from("keypad/state/key/1"), true),
+                (String::from("keypad/state/keypad"), true),
+            ]
+        );
+    }
+}
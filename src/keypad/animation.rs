@@ -0,0 +1,330 @@
+use std::f32::consts::TAU;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::util::Rgb;
+
+/// How far into the current period the animation is, between 0 and 1
+fn phase(elapsed: Duration, period: Duration) -> f32 {
+    if period.is_zero() {
+        return 0.0;
+    }
+
+    (elapsed.as_secs_f32() / period.as_secs_f32()).fract()
+}
+
+/// An effect that changes the color of a single key over time
+#[derive(Clone, Debug)]
+pub(crate) enum Effect {
+    /// Fade from the color of the key through all colors and back, one color per period
+    Fade { colors: Vec<Rgb>, period: Duration },
+    /// Fade the color of the key out and in again
+    Breathe { period: Duration },
+    /// Jump to the color and fade back to the color of the key
+    Pulse { color: Rgb, period: Duration },
+    /// Cycle through all hues
+    Rainbow { period: Duration, brightness: u8 },
+}
+
+impl From<&crate::config::EffectConfig> for Effect {
+    fn from(config: &crate::config::EffectConfig) -> Self {
+        match config {
+            crate::config::EffectConfig::Fade { colors, period } => Effect::Fade {
+                colors: colors.clone(),
+                period: *period,
+            },
+            crate::config::EffectConfig::Breathe { period } => Effect::Breathe { period: *period },
+            crate::config::EffectConfig::Pulse { color, period } => Effect::Pulse {
+                color: *color,
+                period: *period,
+            },
+            crate::config::EffectConfig::Rainbow { period, brightness } => Effect::Rainbow {
+                period: *period,
+                brightness: *brightness,
+            },
+        }
+    }
+}
+
+impl Effect {
+    /// The color of a key with the passed color, `elapsed` after the animations started
+    pub(super) fn apply(&self, color: Rgb, elapsed: Duration) -> Rgb {
+        match self {
+            Effect::Fade { colors, period } => {
+                let steps = colors.len() + 1;
+                let position = if period.is_zero() {
+                    0.0
+                } else {
+                    (elapsed.as_secs_f32() / period.as_secs_f32()) % steps as f32
+                };
+                let step = position as usize;
+                let color_at = |step: usize| match step % steps {
+                    0 => color,
+                    step => colors[step - 1],
+                };
+
+                color_at(step).mix(color_at(step + 1), position.fract())
+            }
+            Effect::Breathe { period } => {
+                let phase = phase(elapsed, *period);
+                color.scale((1.0 + (TAU * phase).cos()) / 2.0)
+            }
+            Effect::Pulse {
+                color: pulse,
+                period,
+            } => {
+                let phase = phase(elapsed, *period);
+                color.mix(*pulse, (1.0 - phase).powi(2))
+            }
+            Effect::Rainbow { period, brightness } => {
+                Rgb::from_hue(phase(elapsed, *period), *brightness)
+            }
+        }
+    }
+}
+
+/// An effect that spans all keys of the keypad
+#[derive(Clone, Debug)]
+pub(crate) enum PadEffect {
+    /// Light up one key after the other, in the order of their indices
+    Chase {
+        color: Rgb,
+        period: Duration,
+        duration: Option<Duration>,
+    },
+    /// A wave travelling from the left to the right column
+    Wave {
+        color: Rgb,
+        period: Duration,
+        duration: Option<Duration>,
+    },
+    /// A ring spreading from the key that started the effect, until it passed all keys
+    Ripple { color: Rgb, duration: Duration },
+}
+
+impl From<&crate::config::PadEffectConfig> for PadEffect {
+    fn from(config: &crate::config::PadEffectConfig) -> Self {
+        match config {
+            crate::config::PadEffectConfig::Chase {
+                color,
+                period,
+                duration,
+            } => PadEffect::Chase {
+                color: *color,
+                period: *period,
+                duration: *duration,
+            },
+            crate::config::PadEffectConfig::Wave {
+                color,
+                period,
+                duration,
+            } => PadEffect::Wave {
+                color: *color,
+                period: *period,
+                duration: *duration,
+            },
+            crate::config::PadEffectConfig::Ripple { color, duration } => PadEffect::Ripple {
+                color: *color,
+                duration: *duration,
+            },
+        }
+    }
+}
+
+impl PadEffect {
+    /// How long the effect runs, or `None` if it runs until it is stopped
+    fn duration(&self) -> Option<Duration> {
+        match self {
+            PadEffect::Chase { duration, .. } | PadEffect::Wave { duration, .. } => *duration,
+            PadEffect::Ripple { duration, .. } => Some(*duration),
+        }
+    }
+}
+
+/// A change of the whole-pad effect, requested by an action
+#[derive(Clone, Debug)]
+pub(crate) enum EffectRequest {
+    Start(PadEffect),
+    Stop,
+}
+
+/// A whole-pad effect that is currently shown
+#[derive(Clone, Debug)]
+pub(super) struct RunningPadEffect {
+    effect: PadEffect,
+    /// Row and column of the key that started the effect
+    origin: (u8, u8),
+    started_at: Instant,
+}
+
+impl RunningPadEffect {
+    pub(super) fn new(effect: PadEffect, origin: (u8, u8), now: Instant) -> Self {
+        Self {
+            effect,
+            origin,
+            started_at: now,
+        }
+    }
+
+    pub(super) fn is_finished(&self, now: Instant) -> bool {
+        self.effect
+            .duration()
+            .is_some_and(|duration| now >= self.started_at + duration)
+    }
+
+    /// The color of the key at the row and column, on a keypad of the passed size
+    pub(super) fn apply(
+        &self,
+        color: Rgb,
+        (row, column): (u8, u8),
+        (rows, columns): (u8, u8),
+        now: Instant,
+    ) -> Rgb {
+        let elapsed = now.saturating_duration_since(self.started_at);
+        match &self.effect {
+            PadEffect::Chase {
+                color: chase,
+                period,
+                ..
+            } => {
+                let key_count = usize::from(rows) * usize::from(columns);
+                let index = usize::from(row) * usize::from(columns) + usize::from(column);
+                let head = (phase(elapsed, *period) * key_count as f32) as usize;
+
+                if index == head {
+                    *chase
+                } else if (index + 1) % key_count == head {
+                    // The key before the head leaves a trail
+                    color.mix(*chase, 0.5)
+                } else {
+                    color
+                }
+            }
+            PadEffect::Wave {
+                color: wave,
+                period,
+                ..
+            } => {
+                let offset = f32::from(column) / f32::from(columns);
+                let phase = phase(elapsed, *period) - offset;
+                color.mix(*wave, (1.0 + (TAU * phase).sin()) / 2.0)
+            }
+            PadEffect::Ripple {
+                color: ripple,
+                duration,
+            } => {
+                let distance_to = |row: u8, column: u8| {
+                    let rows = f32::from(row) - f32::from(self.origin.0);
+                    let columns = f32::from(column) - f32::from(self.origin.1);
+                    rows.hypot(columns)
+                };
+                let corners = [
+                    (0, 0),
+                    (0, columns - 1),
+                    (rows - 1, 0),
+                    (rows - 1, columns - 1),
+                ];
+                let max_distance = corners
+                    .into_iter()
+                    .map(|(row, column)| distance_to(row, column))
+                    .fold(0.0, f32::max);
+
+                let progress = if duration.is_zero() {
+                    1.0
+                } else {
+                    elapsed.as_secs_f32() / duration.as_secs_f32()
+                };
+                // The ring starts on the origin and has left the farthest key at the end
+                let radius = progress * (max_distance + 1.0);
+                let intensity = 1.0 - (distance_to(row, column) - radius).abs();
+                color.mix(*ripple, intensity)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::Effect;
+    use super::PadEffect;
+    use super::RunningPadEffect;
+    use crate::util::Rgb;
+
+    #[test]
+    fn test_key_effects() {
+        let (black, white) = (Rgb::from([0, 0, 0]), Rgb::from([200, 200, 200]));
+        let period = Duration::from_secs(1);
+        let at = |millis| Duration::from_millis(millis);
+
+        let breathe = Effect::Breathe { period };
+        assert_eq!(breathe.apply(white, at(0)), white);
+        assert_eq!(breathe.apply(white, at(500)), black);
+        assert_eq!(breathe.apply(white, at(1000)), white);
+
+        let fade = Effect::Fade {
+            colors: vec![white],
+            period,
+        };
+        assert_eq!(fade.apply(black, at(500)), Rgb::from([100, 100, 100]));
+        assert_eq!(fade.apply(black, at(1000)), white);
+        assert_eq!(fade.apply(black, at(2000)), black);
+
+        let pulse = Effect::Pulse {
+            color: white,
+            period,
+        };
+        assert_eq!(pulse.apply(black, at(0)), white);
+        assert_eq!(pulse.apply(black, at(999)), black);
+
+        let rainbow = Effect::Rainbow {
+            period: Duration::from_secs(3),
+            brightness: 100,
+        };
+        assert_eq!(rainbow.apply(black, at(0)), Rgb::from([100, 0, 0]));
+        assert_eq!(rainbow.apply(black, at(1000)), Rgb::from([0, 100, 0]));
+        assert_eq!(rainbow.apply(black, at(2000)), Rgb::from([0, 0, 100]));
+    }
+
+    #[test]
+    fn test_pad_effects() {
+        let (black, white) = (Rgb::from([0, 0, 0]), Rgb::from([200, 200, 200]));
+        let now = Instant::now();
+        let size = (2, 2);
+
+        let chase = RunningPadEffect::new(
+            PadEffect::Chase {
+                color: white,
+                period: Duration::from_secs(4),
+                duration: None,
+            },
+            (0, 0),
+            now,
+        );
+        let later = now + Duration::from_secs(1);
+        assert_eq!(chase.apply(black, (0, 1), size, later), white);
+        assert_eq!(
+            chase.apply(black, (0, 0), size, later),
+            Rgb::from([100, 100, 100])
+        );
+        assert_eq!(chase.apply(black, (1, 0), size, later), black);
+        assert!(!chase.is_finished(now + Duration::from_secs(3600)));
+
+        let ripple = RunningPadEffect::new(
+            PadEffect::Ripple {
+                color: white,
+                duration: Duration::from_secs(1),
+            },
+            (1, 1),
+            now,
+        );
+        assert_eq!(ripple.apply(black, (1, 1), size, now), white);
+        assert_eq!(ripple.apply(black, (0, 0), size, now), black);
+        assert!(!ripple.is_finished(now));
+        assert!(ripple.is_finished(now + Duration::from_secs(1)));
+    }
+}
@@ -1,3 +1,20 @@
 pub const KEYPAD_EVENT_TOPIC: &str = "arr/out";
 pub const KEYPAD_COLOR_RELEASED_TOPIC: &str = "arr/pressed";
 pub const KEYPAD_COLOR_PRESSED_TOPIC: &str = "arr/released";
+
+pub const AVAILABILITY_ONLINE: &str = "online";
+pub const AVAILABILITY_OFFLINE: &str = "offline";
+
+/// The name of the page configured in the `[keypad]` section
+pub const DEFAULT_PAGE: &str = "default";
+
+pub const DEFAULT_LONG_PRESS_THRESHOLD: std::time::Duration = std::time::Duration::from_millis(500);
+pub const DEFAULT_TAP_WINDOW: std::time::Duration = std::time::Duration::from_millis(300);
+pub const DEFAULT_EXEC_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+pub const DEFAULT_HTTP_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+pub const DEFAULT_MQTT_KEEPALIVE: std::time::Duration = std::time::Duration::from_secs(60);
+pub const MQTT_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+pub const RECONNECT_MIN_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+pub const RECONNECT_MAX_DELAY: std::time::Duration = std::time::Duration::from_secs(60);
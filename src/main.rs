@@ -3,16 +3,23 @@ use std::str::FromStr;
 use clap::Parser;
 use futures::StreamExt;
 use miette::IntoDiagnostic;
-use mqtt_format::v5::packets::MqttPacket;
-use mqtt_format::v5::packets::publish::MPublish;
 use tracing_subscriber::Layer;
 use tracing_subscriber::layer::SubscriberExt;
 
 mod action;
+mod brightness;
+mod check;
 mod cli;
 mod config;
+mod event;
+mod homeassistant;
 mod keypad;
 mod konst;
+mod mqtt;
+mod reload;
+mod report;
+mod state_topic;
+mod template;
 mod util;
 
 #[tokio::main]
@@ -26,81 +33,149 @@ async fn main() -> miette::Result<()> {
     setup_logging(cli.logging.map(From::from));
 
     tracing::info!("Parsing config now");
-    let config = crate::config::Config::load(cli.config_path)
+    let config_path = crate::config::Config::find_path(cli.config_path).into_diagnostic()?;
+    if let Some(crate::cli::Command::CheckConfig) = cli.command {
+        crate::check::check_config(&config_path).await?;
+        println!("{config_path}: no problems found");
+        return Ok(());
+    }
+
+    let mut config = crate::config::Config::load(&config_path)
         .await
         .into_diagnostic()?;
 
-    tracing::info!(
-        broker = config.mqtt_broker_addr,
-        port = config.mqtt_broker_port,
-        "Starting MQTT client now"
-    );
-    let mqtt = cloudmqtt::CloudmqttClient::new(format!(
-        "{}:{}",
-        config.mqtt_broker_addr, config.mqtt_broker_port
-    ))
-    .await;
-
-    let event_topic_name = format!(
-        "{}/{}",
-        config.mqtt_subscribe_prefix,
-        crate::konst::KEYPAD_EVENT_TOPIC
-    );
-    tracing::info!(topic = event_topic_name, "Subscribing event topic now");
-    let mut events = mqtt.subscribe(event_topic_name).await;
+    let mut mqtt = crate::mqtt::connect(&config).await?;
+    crate::mqtt::publish_availability(&mqtt, &config, true).await;
+    let mut subscriptions = crate::mqtt::Subscriptions::subscribe(&mqtt, &config).await;
 
-    let mut key_pad_state = crate::keypad::KeypadState::from_config(&config);
+    let (action_errors, mut failed_actions) = tokio::sync::mpsc::unbounded_channel();
+    let mut key_pad_state = crate::keypad::KeypadState::from_config(&config, &action_errors);
     key_pad_state.publish(&mqtt, &config).await;
+    crate::homeassistant::publish_discovery(&mqtt, &config).await;
 
     let mut interval = tokio::time::interval(config.interval_duration.unwrap_or(cli.interval));
 
-    let mut key_subscriptions = (0..=24)
-        .fold(mqtt.subscription_builder(), |builder, i| {
-            let topic = format!("{}/key/{i}", config.mqtt_control_prefix);
-            builder.with_subscription(topic)
-        })
-        .build()
-        .await;
+    let mut reload_triggers = crate::reload::ReloadTriggers::new(&config_path).into_diagnostic()?;
+    let mut reconnect = crate::mqtt::Reconnect::new(tokio::time::Instant::now());
 
     loop {
         tokio::select! {
             _ = tokio::signal::ctrl_c() => {
                 tracing::warn!("ctrl-c received, cancelling application.");
+                crate::mqtt::publish_availability(&mqtt, &config, false).await;
+                mqtt.disconnect().await;
                 break
             }
 
+            trigger = reload_triggers.next() => {
+                tracing::info!(?trigger, path = %config_path, "Reloading config");
+                let new_config = match crate::config::Config::load(&config_path).await {
+                    Ok(new_config) => new_config,
+                    Err(error) => {
+                        let report = miette::Report::new(error);
+                        tracing::error!("Failed to reload config, keeping the current one: {report:?}");
+                        continue
+                    }
+                };
+
+                if crate::mqtt::needs_reconnect(&config, &new_config) {
+                    let new_mqtt = match crate::mqtt::connect(&new_config).await {
+                        Ok(mqtt) => mqtt,
+                        Err(error) => {
+                            let report = miette::Report::new(error);
+                            tracing::error!("Failed to reconnect, keeping the current config: {report:?}");
+                            continue
+                        }
+                    };
+                    crate::mqtt::publish_availability(&mqtt, &config, false).await;
+                    crate::mqtt::publish_availability(&new_mqtt, &new_config, true).await;
+                    mqtt = new_mqtt;
+                    subscriptions = crate::mqtt::Subscriptions::subscribe(&mqtt, &new_config).await;
+                    reconnect.connected(tokio::time::Instant::now());
+                } else if crate::mqtt::needs_resubscribe(&config, &new_config) {
+                    subscriptions = crate::mqtt::Subscriptions::subscribe(&mqtt, &new_config).await;
+                }
+
+                if config.interval_duration != new_config.interval_duration {
+                    interval = tokio::time::interval(new_config.interval_duration.unwrap_or(cli.interval));
+                }
+
+                let mut new_key_pad_state = crate::keypad::KeypadState::from_config(&new_config, &action_errors);
+                new_key_pad_state.adopt_runtime_state(&key_pad_state);
+                key_pad_state = new_key_pad_state;
+                config = new_config;
+
+                key_pad_state.publish(&mqtt, &config).await;
+                crate::homeassistant::publish_discovery(&mqtt, &config).await;
+            }
+
+            Some(error) = failed_actions.recv() => {
+                tracing::error!(?error, "Executing action yielded error");
+            }
+
             _tick = interval.tick() => {
                 tracing::info!("Publishing key state");
+                key_pad_state.advance_blinking();
                 key_pad_state.publish(&mqtt, &config).await
             },
 
-            packet = key_subscriptions.next() => {
-                let Some(packet) = packet else {
-                    tracing::warn!("control subscription stream seems to have closed");
-                    continue
+            _ = crate::util::sleep_until(reconnect.at) => {
+                let new_mqtt = match crate::mqtt::connect(&config).await {
+                    Ok(mqtt) => mqtt,
+                    Err(error) => {
+                        let report = miette::Report::new(error);
+                        tracing::error!("Failed to reconnect: {report:?}");
+                        reconnect.retry(tokio::time::Instant::now());
+                        continue
+                    }
                 };
+                mqtt = new_mqtt;
+                subscriptions = crate::mqtt::Subscriptions::subscribe(&mqtt, &config).await;
+                reconnect.connected(tokio::time::Instant::now());
+                tracing::info!("Reconnected");
 
-                let MqttPacket::Publish(publish) = packet.get_packet() else {
-                    tracing::debug!(?packet, "Ignoring non-publish packet");
-                    continue
-                };
+                crate::mqtt::publish_availability(&mqtt, &config, true).await;
+                key_pad_state.publish(&mqtt, &config).await;
+                key_pad_state.forget_published_state();
+                crate::homeassistant::publish_discovery(&mqtt, &config).await;
+            },
+
+            _ = crate::util::sleep_until(key_pad_state.next_frame()) => {
+                key_pad_state.publish(&mqtt, &config).await
+            },
+
+            _ = crate::util::sleep_until(key_pad_state.next_deadline()) => {
+                key_pad_state.poll_timers(&mqtt).await;
+                key_pad_state.publish(&mqtt, &config).await
+            },
 
-                tracing::info!(?publish, "Received control packet");
-                let Some(target_key) = publish.topic_name.split('/').nth(2) else {
-                    tracing::warn!(topic = publish.topic_name, "No target key found in topic name");
+            message = subscriptions.control.next() => {
+                let Some(message) = message else {
+                    tracing::warn!("control subscription stream seems to have closed");
+                    subscriptions = crate::mqtt::Subscriptions::pending();
+                    reconnect.schedule(tokio::time::Instant::now());
                     continue
                 };
 
-                let target_key = match target_key.parse::<u8>() {
-                    Ok(k) => k,
-                    Err(error) => {
-                        tracing::warn!(?error, "Could not parse target key as number");
+                tracing::info!(?message, "Received control packet");
+                if message.topic == action::brightness_topic(&config.mqtt_control_prefix) {
+                    let level = std::str::from_utf8(&message.payload).ok().and_then(|level| level.trim().parse().ok());
+                    let Some(level) = level else {
+                        tracing::warn!(payload = ?message.payload, "Brightness must be a percentage");
                         continue
-                    }
+                    };
+                    key_pad_state.set_brightness(level);
+                    key_pad_state.publish(&mqtt, &config).await;
+                    continue
+                }
+
+                let Some(target) = action::ControlTarget::from_topic(&config.mqtt_control_prefix, &message.topic) else {
+                    tracing::warn!(topic = message.topic, "No target found in topic name");
+                    continue
                 };
-                tracing::debug!(?target_key, "Found target key");
+                tracing::debug!(?target, "Found target");
 
-                let control_actions: action::ControlPacket = match serde_json::from_slice(publish.payload) {
+                let control_actions = match action::ControlPacket::parse(&message.payload, &config.palette) {
                     Ok(a) => a,
                     Err(error) => {
                         tracing::warn!(?error, "Failed to parse control action");
@@ -111,19 +186,42 @@ async fn main() -> miette::Result<()> {
                 tracing::info!(n = control_actions.actions.len(), "Received control actions");
                 for action in control_actions.actions.into_iter() {
                     tracing::info!(?action, "Applying control action");
-                    key_pad_state.run_ctrl_action_on_key(target_key, action);
+                    key_pad_state.run_ctrl_action(&target, action);
+                }
+                key_pad_state.publish(&mqtt, &config).await;
+            },
+
+            message = subscriptions.states.next() => {
+                let Some(message) = message else {
+                    tracing::warn!("state subscription stream seems to have closed");
+                    subscriptions = crate::mqtt::Subscriptions::pending();
+                    reconnect.schedule(tokio::time::Instant::now());
+                    continue
+                };
+
+                tracing::debug!(?message, "Received state");
+                key_pad_state.apply_state_message(&message.topic, &message.payload);
+            },
+
+            message = subscriptions.homeassistant.next() => {
+                let Some(message) = message else {
+                    tracing::warn!("Home Assistant subscription stream seems to have closed");
+                    subscriptions = crate::mqtt::Subscriptions::pending();
+                    reconnect.schedule(tokio::time::Instant::now());
+                    continue
+                };
+
+                if crate::homeassistant::is_birth_message(&message) {
+                    crate::homeassistant::publish_discovery(&mqtt, &config).await;
+                    crate::mqtt::publish_availability(&mqtt, &config, true).await;
                 }
             },
 
-            next_event = events.next() => {
+            next_event = subscriptions.events.next() => {
                 if let Some(event) = next_event {
                     tracing::info!("Received event");
-                    let MqttPacket::Publish(MPublish { payload, .. }) = event.get_packet() else {
-                        tracing::debug!("Ignoring non-publish message");
-                        continue;
-                    };
 
-                    let num = match std::str::from_utf8(payload).map(f32::from_str) {
+                    let num = match std::str::from_utf8(&event.payload).map(f32::from_str) {
                         Ok(Ok(p)) => p,
                         Err(error) => {
                             tracing::warn!(?error, "Failed to parse payload");
@@ -140,9 +238,15 @@ async fn main() -> miette::Result<()> {
                     } else {
                         key_pad_state.pressed(num.abs() as u8, &mqtt).await;
                     }
+                } else {
+                    tracing::warn!("event subscription stream seems to have closed");
+                    subscriptions = crate::mqtt::Subscriptions::pending();
+                    reconnect.schedule(tokio::time::Instant::now());
                 }
             }
         }
+
+        key_pad_state.publish_state(&mqtt, &config).await;
     }
 
     Ok(())
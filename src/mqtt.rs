@@ -0,0 +1,601 @@
+use futures::StreamExt;
+use futures::stream::LocalBoxStream;
+
+mod client;
+mod packet;
+#[cfg(test)]
+pub(crate) mod test_broker;
+mod tls;
+
+pub use client::Client;
+
+/// A message that was published on one of the subscribed topics
+#[derive(Debug)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+pub enum MqttError {
+    #[error("Failed to read the MQTT password from '{path}'")]
+    PasswordFile {
+        path: camino::Utf8PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Failed to read the MQTT password from the environment variable '{name}'")]
+    #[diagnostic(help("Make sure that the variable is set and valid unicode"))]
+    PasswordEnv {
+        name: String,
+        #[source]
+        source: std::env::VarError,
+    },
+
+    #[error("Failed to read the TLS file '{path}'")]
+    TlsFile {
+        path: camino::Utf8PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("The TLS file '{path}' does not contain a valid PEM certificate or key")]
+    InvalidTlsFile {
+        path: camino::Utf8PathBuf,
+        #[source]
+        source: rustls::pki_types::pem::Error,
+    },
+
+    #[error("Invalid TLS configuration")]
+    Tls(#[source] rustls::Error),
+
+    #[error("'{0}' is not a valid server name for TLS")]
+    #[diagnostic(help("Set mqtt_broker_addr to the host name in the certificate of the broker"))]
+    InvalidServerName(String),
+
+    #[error("Failed to connect to the MQTT broker at {broker}")]
+    #[diagnostic(help("Make sure that the broker is running and reachable"))]
+    Connect {
+        broker: String,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("The MQTT broker at {broker} did not accept the connection in time")]
+    #[diagnostic(help(
+        "Make sure that mqtt_broker_addr and mqtt_broker_port point to an MQTT broker"
+    ))]
+    Timeout { broker: String },
+
+    #[error("The MQTT broker rejected the connection: {reason}")]
+    Rejected {
+        reason: &'static str,
+        #[help]
+        help: Option<&'static str>,
+    },
+
+    #[error("The MQTT broker sent an unexpected packet: {0}")]
+    UnexpectedPacket(String),
+
+    #[error("Failed to talk MQTT with the broker")]
+    Protocol(#[from] packet::PacketError),
+}
+
+impl MqttError {
+    /// The error for the return code of a CONNACK packet
+    fn rejected(return_code: u8) -> Self {
+        let (reason, help) = match return_code {
+            1 => (
+                "unacceptable protocol version",
+                Some("The broker has to support MQTT 3.1.1"),
+            ),
+            2 => ("client ID rejected", Some("Set another mqtt_client_id")),
+            3 => ("server unavailable", None),
+            4 => (
+                "bad username or password",
+                Some("Check mqtt_username and the configured password"),
+            ),
+            5 => (
+                "not authorized",
+                Some("Check mqtt_username and the configured password"),
+            ),
+            _ => ("unknown return code", None),
+        };
+        Self::Rejected { reason, help }
+    }
+}
+
+pub async fn connect(config: &crate::config::Config) -> Result<Client, MqttError> {
+    let (username, password) = match credentials(config).await? {
+        Some((username, password)) => (Some(username), password.map(String::into_bytes)),
+        None => (None, None),
+    };
+    let tls = match config.mqtt_tls.as_ref() {
+        Some(tls) => Some(tls::client_config(tls).await?),
+        None => None,
+    };
+    let keepalive = config
+        .mqtt_keepalive
+        .unwrap_or(crate::konst::DEFAULT_MQTT_KEEPALIVE);
+
+    tracing::info!(
+        broker = config.mqtt_broker_addr,
+        port = config.mqtt_broker_port,
+        tls = tls.is_some(),
+        "Starting MQTT client now"
+    );
+    let options = client::Options {
+        host: config.mqtt_broker_addr.clone(),
+        port: config.mqtt_broker_port,
+        tls,
+        connect: packet::Connect {
+            // The broker assigns an ID if it is empty
+            client_id: config.mqtt_client_id.clone().unwrap_or_default(),
+            keepalive_secs: keepalive.as_secs().try_into().unwrap_or(u16::MAX),
+            // The broker marks the keypad offline if the connection is lost
+            will: config
+                .mqtt_availability_topic
+                .as_ref()
+                .map(|topic| packet::Will {
+                    topic: topic.to_string(),
+                    payload: crate::konst::AVAILABILITY_OFFLINE.into(),
+                    qos: Qos::AtLeast,
+                    retain: true,
+                }),
+            username,
+            password,
+        },
+        timeout: crate::konst::MQTT_CONNECT_TIMEOUT,
+    };
+
+    client::connect(options).await
+}
+
+/// Publish whether the keypad is available on the availability topic, if configured
+pub async fn publish_availability(mqtt: &Client, config: &crate::config::Config, online: bool) {
+    let Some(topic) = config.mqtt_availability_topic.as_ref() else {
+        return;
+    };
+
+    let payload = if online {
+        crate::konst::AVAILABILITY_ONLINE
+    } else {
+        crate::konst::AVAILABILITY_OFFLINE
+    };
+    tracing::info!(topic, payload, "Publishing availability");
+    publish(mqtt, topic.to_string(), payload, Qos::AtLeast, true).await
+}
+
+/// The quality of service level of a published message
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[cfg_attr(test, derive(serde::Serialize))]
+#[serde(try_from = "u8", into = "u8")]
+pub enum Qos {
+    /// Delivered at most once
+    #[default]
+    AtMost,
+    /// Delivered at least once
+    AtLeast,
+    /// Delivered exactly once
+    Exactly,
+}
+
+impl TryFrom<u8> for Qos {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Qos::AtMost),
+            1 => Ok(Qos::AtLeast),
+            2 => Ok(Qos::Exactly),
+            value => Err(format!("invalid QoS level {value}, expected 0, 1 or 2")),
+        }
+    }
+}
+
+impl From<Qos> for u8 {
+    fn from(qos: Qos) -> Self {
+        match qos {
+            Qos::AtMost => 0,
+            Qos::AtLeast => 1,
+            Qos::Exactly => 2,
+        }
+    }
+}
+
+/// Publish a message, all messages are published through this function
+pub async fn publish(
+    mqtt: &Client,
+    topic: String,
+    payload: impl Into<Vec<u8>>,
+    qos: Qos,
+    retain: bool,
+) {
+    let payload = payload.into();
+    tracing::trace!(topic, ?qos, retain, len = payload.len(), "Publishing");
+    mqtt.publish(packet::Publish {
+        topic,
+        payload,
+        qos,
+        retain,
+        duplicate: false,
+        packet_id: None,
+    })
+}
+
+/// The username and password to authenticate with, if configured
+async fn credentials(
+    config: &crate::config::Config,
+) -> Result<Option<(String, Option<String>)>, MqttError> {
+    let Some(username) = config.mqtt_username.as_ref() else {
+        return Ok(None);
+    };
+
+    let password = if let Some(path) = config.mqtt_password_file.as_ref() {
+        let password =
+            tokio::fs::read_to_string(path)
+                .await
+                .map_err(|source| MqttError::PasswordFile {
+                    path: path.clone(),
+                    source,
+                })?;
+        Some(password.trim_end().to_string())
+    } else if let Some(name) = config.mqtt_password_env.as_ref() {
+        let password = std::env::var(name).map_err(|source| MqttError::PasswordEnv {
+            name: name.to_string(),
+            source,
+        })?;
+        Some(password)
+    } else {
+        config.mqtt_password.clone()
+    };
+
+    Ok(Some((username.to_string(), password)))
+}
+
+/// Whether switching between the configs requires connecting to the broker again
+pub fn needs_reconnect(old: &crate::config::Config, new: &crate::config::Config) -> bool {
+    old.mqtt_broker_addr != new.mqtt_broker_addr
+        || old.mqtt_broker_port != new.mqtt_broker_port
+        || old.mqtt_client_id != new.mqtt_client_id
+        || old.mqtt_username != new.mqtt_username
+        || old.mqtt_password != new.mqtt_password
+        || old.mqtt_password_file != new.mqtt_password_file
+        || old.mqtt_password_env != new.mqtt_password_env
+        || old.mqtt_keepalive != new.mqtt_keepalive
+        || old.mqtt_tls != new.mqtt_tls
+        || old.mqtt_availability_topic != new.mqtt_availability_topic
+}
+
+/// Whether switching between the configs requires subscribing to other topics
+pub fn needs_resubscribe(old: &crate::config::Config, new: &crate::config::Config) -> bool {
+    old.mqtt_subscribe_prefix != new.mqtt_subscribe_prefix
+        || old.mqtt_control_prefix != new.mqtt_control_prefix
+        || old.keypad.rows != new.keypad.rows
+        || old.keypad.columns != new.keypad.columns
+        || old.groups.keys().ne(new.groups.keys())
+        || old.homeassistant.as_ref().map(|h| &h.discovery_prefix)
+            != new.homeassistant.as_ref().map(|h| &h.discovery_prefix)
+        || old.state_topics() != new.state_topics()
+}
+
+pub struct Subscriptions {
+    /// Button events of the keypad hardware
+    pub events: LocalBoxStream<'static, Message>,
+
+    /// Control messages for keys, see [`crate::action::ControlTarget`]
+    pub control: LocalBoxStream<'static, Message>,
+
+    /// State of devices that keys are colored by
+    pub states: LocalBoxStream<'static, Message>,
+
+    /// Status of Home Assistant, if discovery is enabled
+    pub homeassistant: LocalBoxStream<'static, Message>,
+}
+
+impl Subscriptions {
+    /// Subscriptions that never yield a message, while there is no connection
+    pub fn pending() -> Self {
+        Self {
+            events: futures::stream::pending().boxed_local(),
+            control: futures::stream::pending().boxed_local(),
+            states: futures::stream::pending().boxed_local(),
+            homeassistant: futures::stream::pending().boxed_local(),
+        }
+    }
+
+    pub async fn subscribe(mqtt: &Client, config: &crate::config::Config) -> Self {
+        let event_topic_name = format!(
+            "{}/{}",
+            config.mqtt_subscribe_prefix,
+            crate::konst::KEYPAD_EVENT_TOPIC
+        );
+        tracing::info!(topic = event_topic_name, "Subscribing event topic now");
+        let events = mqtt.subscribe(vec![event_topic_name]).await.boxed_local();
+
+        let prefix = &config.mqtt_control_prefix;
+        let control_topics = std::iter::once(format!("{prefix}/all"))
+            .chain(std::iter::once(crate::action::brightness_topic(prefix)))
+            .chain((0..config.keypad.key_count()).map(|i| format!("{prefix}/key/{i}")))
+            .chain((0..config.keypad.rows).map(|row| format!("{prefix}/row/{row}")))
+            .chain((0..config.keypad.columns).map(|column| format!("{prefix}/col/{column}")))
+            .chain(
+                config
+                    .groups
+                    .keys()
+                    .map(|name| format!("{prefix}/group/{name}")),
+            );
+        let control = mqtt.subscribe(control_topics.collect()).await.boxed_local();
+
+        let state_topics = config.state_topics();
+        let states = if state_topics.is_empty() {
+            futures::stream::pending().boxed_local()
+        } else {
+            tracing::info!(topics = ?state_topics, "Subscribing state topics now");
+            let topics = state_topics.into_iter().map(str::to_string).collect();
+            mqtt.subscribe(topics).await.boxed_local()
+        };
+
+        let homeassistant = match config.homeassistant.as_ref() {
+            None => futures::stream::pending().boxed_local(),
+            Some(homeassistant) => {
+                let topic = crate::homeassistant::status_topic(homeassistant);
+                tracing::info!(topic, "Subscribing Home Assistant status topic now");
+                mqtt.subscribe(vec![topic]).await.boxed_local()
+            }
+        };
+
+        Self {
+            events,
+            control,
+            states,
+            homeassistant,
+        }
+    }
+}
+
+/// Schedules reconnects to the broker with exponential backoff
+#[derive(Debug)]
+pub struct Reconnect {
+    /// When to connect again, if the connection was lost
+    pub at: Option<tokio::time::Instant>,
+    delay: std::time::Duration,
+    connected_at: tokio::time::Instant,
+}
+
+impl Reconnect {
+    pub fn new(now: tokio::time::Instant) -> Self {
+        Self {
+            at: None,
+            delay: crate::konst::RECONNECT_MIN_DELAY,
+            connected_at: now,
+        }
+    }
+
+    /// Schedule a reconnect after the connection was lost
+    ///
+    /// The delay doubles with every attempt, unless the last connection was up for longer than
+    /// the maximum delay.
+    pub fn schedule(&mut self, now: tokio::time::Instant) {
+        if self.at.is_some() {
+            return;
+        }
+
+        if now.duration_since(self.connected_at) >= crate::konst::RECONNECT_MAX_DELAY {
+            self.delay = crate::konst::RECONNECT_MIN_DELAY;
+        }
+
+        tracing::warn!("Connection lost");
+        self.retry(now);
+    }
+
+    /// Schedule another attempt after reconnecting failed
+    pub fn retry(&mut self, now: tokio::time::Instant) {
+        tracing::info!(delay = ?self.delay, "Reconnecting after delay");
+        self.at = Some(now + self.delay);
+        self.delay = (self.delay * 2).min(crate::konst::RECONNECT_MAX_DELAY);
+    }
+
+    /// Record that the connection was established again
+    pub fn connected(&mut self, now: tokio::time::Instant) {
+        self.at = None;
+        self.connected_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::MqttError;
+    use super::Qos;
+    use super::Reconnect;
+    use super::packet::Connect;
+    use super::packet::Packet;
+    use super::packet::Will;
+    use super::test_broker::TestBroker;
+
+    fn config(port: u16, connection: &str) -> crate::config::Config {
+        let config_str = format!(
+            r#"
+            mqtt_broker_addr = "127.0.0.1"
+            mqtt_broker_port = {port}
+            mqtt_subscribe_prefix = "keypad"
+            mqtt_control_prefix = "keypad/control"
+            interval_duration = "1s"
+            {connection}
+
+            [keypad]
+            rows = 1
+            columns = 1
+            pad_0_0 = {{ released = [0,0,0], pressed = [0,0,0], alternative = [0,0,0], on_press = [], on_release = [] }}
+            "#
+        );
+        toml::from_str(&config_str).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connect_options() {
+        let password_file =
+            std::env::temp_dir().join(format!("keypad-test-mqtt-password-{}", std::process::id()));
+        tokio::fs::write(&password_file, "secret\n").await.unwrap();
+
+        let broker = TestBroker::bind().await;
+        let configured = config(
+            broker.port,
+            &format!(
+                r#"
+                mqtt_client_id = "keypad-util"
+                mqtt_username = "keypad"
+                mqtt_password_file = "{}"
+                mqtt_keepalive = "30s"
+                mqtt_availability_topic = "keypad/availability"
+                "#,
+                password_file.display()
+            ),
+        );
+        let (accepted, client) = tokio::join!(broker.accept(0), super::connect(&configured));
+        tokio::fs::remove_file(&password_file).await.unwrap();
+        assert!(client.is_ok());
+
+        let (_, connect) = accepted;
+        assert_eq!(
+            connect,
+            Connect {
+                client_id: String::from("keypad-util"),
+                keepalive_secs: 30,
+                will: Some(Will {
+                    topic: String::from("keypad/availability"),
+                    payload: b"offline".to_vec(),
+                    qos: Qos::AtLeast,
+                    retain: true,
+                }),
+                username: Some(String::from("keypad")),
+                password: Some(b"secret".to_vec()),
+            }
+        );
+
+        // Without options the broker assigns the client ID
+        let defaults = config(broker.port, "");
+        let ((_, connect), client) = tokio::join!(broker.accept(0), super::connect(&defaults));
+        assert!(client.is_ok());
+        assert_eq!(connect.client_id, "");
+        assert_eq!(connect.keepalive_secs, 60);
+        assert_eq!(connect.will, None);
+        assert_eq!(connect.username, None);
+        assert_eq!(connect.password, None);
+    }
+
+    #[tokio::test]
+    async fn test_publish_qos_and_retain() {
+        let broker = TestBroker::bind().await;
+        let (mut connection, client) = broker.connect().await;
+
+        super::publish(&client, String::from("a"), "0", Qos::AtMost, true).await;
+        let publish = connection.read_publish().await;
+        assert_eq!(
+            (publish.topic.as_str(), publish.qos, publish.retain),
+            ("a", Qos::AtMost, true)
+        );
+        assert_eq!(publish.packet_id, None);
+
+        super::publish(&client, String::from("b"), "1", Qos::AtLeast, false).await;
+        let publish = connection.read_publish().await;
+        assert_eq!(
+            (publish.topic.as_str(), publish.qos, publish.retain),
+            ("b", Qos::AtLeast, false)
+        );
+        let packet_id = publish.packet_id.unwrap();
+        connection.write(Packet::PubAck(packet_id)).await;
+
+        super::publish(&client, String::from("c"), "2", Qos::Exactly, true).await;
+        let publish = connection.read_publish().await;
+        assert_eq!(
+            (publish.topic.as_str(), publish.qos, publish.retain),
+            ("c", Qos::Exactly, true)
+        );
+        let packet_id = publish.packet_id.unwrap();
+        connection.write(Packet::PubRec(packet_id)).await;
+        assert_eq!(connection.read().await, Packet::PubRel(packet_id));
+        connection.write(Packet::PubComp(packet_id)).await;
+
+        client.disconnect().await;
+        assert_eq!(connection.read().await, Packet::Disconnect);
+    }
+
+    #[tokio::test]
+    async fn test_publish_availability() {
+        let broker = TestBroker::bind().await;
+        let (mut connection, client) = broker.connect().await;
+        let config = config(
+            broker.port,
+            r#"mqtt_availability_topic = "keypad/availability""#,
+        );
+
+        super::publish_availability(&client, &config, true).await;
+        let publish = connection.read_publish().await;
+        assert_eq!(publish.topic, "keypad/availability");
+        assert_eq!(publish.payload, b"online");
+        assert_eq!((publish.qos, publish.retain), (Qos::AtLeast, true));
+    }
+
+    #[tokio::test]
+    async fn test_connect_rejected() {
+        let broker = TestBroker::bind().await;
+        let config = config(
+            broker.port,
+            r#"
+            mqtt_username = "keypad"
+            mqtt_password = "wrong"
+            "#,
+        );
+        let (_, client) = tokio::join!(broker.accept(4), super::connect(&config));
+        let error = client.unwrap_err();
+        assert!(matches!(
+            error,
+            MqttError::Rejected {
+                reason: "bad username or password",
+                ..
+            }
+        ));
+        assert_eq!(
+            error.to_string(),
+            "The MQTT broker rejected the connection: bad username or password"
+        );
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let now = Instant::now();
+        let mut reconnect = Reconnect::new(now);
+
+        let mut delays = Vec::new();
+        for _ in 0..8 {
+            reconnect.schedule(now);
+            delays.push(reconnect.at.unwrap() - now);
+            reconnect.connected(now);
+        }
+        assert_eq!(
+            delays,
+            [1, 2, 4, 8, 16, 32, 60, 60].map(Duration::from_secs)
+        );
+
+        // Losing the connection again while waiting does not delay the reconnect further
+        reconnect.schedule(now);
+        reconnect.schedule(now + Duration::from_secs(30));
+        assert_eq!(reconnect.at, Some(now + Duration::from_secs(60)));
+
+        // Failed attempts keep backing off
+        reconnect.retry(now + Duration::from_secs(60));
+        assert_eq!(reconnect.at, Some(now + Duration::from_secs(120)));
+
+        // A connection that was up for a while starts over with the shortest delay
+        reconnect.connected(now);
+        let later = now + Duration::from_secs(120);
+        reconnect.schedule(later);
+        assert_eq!(reconnect.at, Some(later + Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,507 @@
+//! A minimal MQTT 3.1.1 client, with one task per connection
+//!
+//! The session is always clean, so messages that are in flight when the connection is lost are not
+//! sent again after reconnecting.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+use super::Message;
+use super::MqttError;
+use super::Qos;
+use super::packet::Packet;
+
+/// How to connect to the broker
+pub struct Options {
+    pub host: String,
+    pub port: u16,
+    pub tls: Option<Arc<rustls::ClientConfig>>,
+    /// The CONNECT packet, the keepalive in it is also used to detect a lost connection
+    pub connect: super::packet::Connect,
+    /// How long to wait for the broker to accept the connection
+    pub timeout: Duration,
+}
+
+/// A connection to the broker, which is closed once all clones are dropped
+#[derive(Clone, Debug)]
+pub struct Client {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+#[derive(Debug)]
+enum Command {
+    Publish(super::packet::Publish),
+    Subscribe {
+        filters: Vec<String>,
+        messages: futures::channel::mpsc::UnboundedSender<Message>,
+        subscribed: oneshot::Sender<()>,
+    },
+    Disconnect(oneshot::Sender<()>),
+}
+
+trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+/// Connect to the broker, returning once it accepted the connection
+pub async fn connect(options: Options) -> Result<Client, MqttError> {
+    let broker = format!("{}:{}", options.host, options.port);
+    let keepalive = Duration::from_secs(options.connect.keepalive_secs.into());
+    let stream = tokio::time::timeout(options.timeout, establish(options, &broker))
+        .await
+        .map_err(|_| MqttError::Timeout {
+            broker: broker.clone(),
+        })??;
+    tracing::debug!(broker, "Connected");
+
+    let (mut reader, writer) = tokio::io::split(stream);
+    let (incoming_sender, incoming) = mpsc::unbounded_channel();
+    let reader = tokio::spawn(async move {
+        loop {
+            match super::packet::read(&mut reader).await {
+                Ok(packet) => {
+                    if incoming_sender.send(packet).is_err() {
+                        break;
+                    }
+                }
+                Err(error) => {
+                    tracing::warn!(%error, "Failed to read from the MQTT broker");
+                    break;
+                }
+            }
+        }
+    });
+
+    let (commands, command_receiver) = mpsc::unbounded_channel();
+    let connection = Connection {
+        writer,
+        keepalive,
+        next_packet_id: 0,
+        subscriptions: Vec::new(),
+        pending_subscriptions: BTreeMap::new(),
+        received_qos2: BTreeSet::new(),
+        last_sent: Instant::now(),
+        last_received: Instant::now(),
+    };
+    tokio::spawn(async move {
+        connection.run(command_receiver, incoming).await;
+        reader.abort();
+    });
+
+    Ok(Client { commands })
+}
+
+/// Open the connection and wait for the broker to accept it
+async fn establish(options: Options, broker: &str) -> Result<Box<dyn Transport>, MqttError> {
+    let connect_error = |source| MqttError::Connect {
+        broker: broker.to_string(),
+        source,
+    };
+
+    let tcp = tokio::net::TcpStream::connect((options.host.as_str(), options.port))
+        .await
+        .map_err(connect_error)?;
+    let mut stream: Box<dyn Transport> = match options.tls {
+        Some(tls) => {
+            let server_name = rustls::pki_types::ServerName::try_from(options.host.clone())
+                .map_err(|_| MqttError::InvalidServerName(options.host.clone()))?;
+            let tls = tokio_rustls::TlsConnector::from(tls)
+                .connect(server_name, tcp)
+                .await
+                .map_err(connect_error)?;
+            Box::new(tls)
+        }
+        None => Box::new(tcp),
+    };
+
+    stream
+        .write_all(&Packet::Connect(options.connect).encode())
+        .await
+        .map_err(connect_error)?;
+    match super::packet::read(&mut stream).await? {
+        Packet::ConnAck { return_code: 0, .. } => Ok(stream),
+        Packet::ConnAck { return_code, .. } => Err(MqttError::rejected(return_code)),
+        packet => Err(MqttError::UnexpectedPacket(format!("{packet:?}"))),
+    }
+}
+
+impl Client {
+    /// Queue a message, the packet ID of QoS 1 and 2 messages is assigned by the connection
+    pub fn publish(&self, publish: super::packet::Publish) {
+        if self.commands.send(Command::Publish(publish)).is_err() {
+            tracing::debug!("Not connected, dropping message");
+        }
+    }
+
+    /// Subscribe to the topic filters, the stream ends when the connection is lost
+    pub async fn subscribe(
+        &self,
+        filters: Vec<String>,
+    ) -> futures::channel::mpsc::UnboundedReceiver<Message> {
+        let (messages, receiver) = futures::channel::mpsc::unbounded();
+        let (subscribed, acknowledged) = oneshot::channel();
+        let command = Command::Subscribe {
+            filters,
+            messages,
+            subscribed,
+        };
+        if self.commands.send(command).is_ok() {
+            // Fails if the connection is lost, then the stream ends right away
+            let _ = acknowledged.await;
+        }
+        receiver
+    }
+
+    /// Send all queued messages and disconnect gracefully, so that the broker does not publish the
+    /// last will
+    pub async fn disconnect(&self) {
+        let (done, disconnected) = oneshot::channel();
+        if self.commands.send(Command::Disconnect(done)).is_ok() {
+            let _ = disconnected.await;
+        }
+    }
+}
+
+struct Subscription {
+    filters: Vec<String>,
+    messages: futures::channel::mpsc::UnboundedSender<Message>,
+}
+
+struct Connection<W> {
+    writer: W,
+    keepalive: Duration,
+    next_packet_id: u16,
+    subscriptions: Vec<Subscription>,
+    /// Subscriptions that are waiting for their SUBACK, by packet ID
+    pending_subscriptions: BTreeMap<u16, oneshot::Sender<()>>,
+    /// IDs of received QoS 2 messages that were not released yet, to deliver them only once
+    received_qos2: BTreeSet<u16>,
+    last_sent: Instant,
+    last_received: Instant,
+}
+
+impl<W: AsyncWrite + Unpin> Connection<W> {
+    async fn run(
+        mut self,
+        mut commands: mpsc::UnboundedReceiver<Command>,
+        mut incoming: mpsc::UnboundedReceiver<Packet>,
+    ) {
+        // A keepalive of zero disables it
+        let mut keepalive_check =
+            tokio::time::interval((self.keepalive / 2).max(Duration::from_secs(1)));
+
+        let result = loop {
+            let result = tokio::select! {
+                command = commands.recv() => match command {
+                    Some(Command::Disconnect(done)) => {
+                        let result = self.write(Packet::Disconnect).await;
+                        let _ = done.send(());
+                        break result;
+                    }
+                    Some(command) => self.handle_command(command).await,
+                    None => break self.write(Packet::Disconnect).await,
+                },
+
+                packet = incoming.recv() => match packet {
+                    Some(packet) => {
+                        self.last_received = Instant::now();
+                        self.handle_packet(packet).await
+                    }
+                    None => break Err(std::io::ErrorKind::UnexpectedEof.into()),
+                },
+
+                _ = keepalive_check.tick(), if !self.keepalive.is_zero() => {
+                    if self.last_received.elapsed() > self.keepalive * 3 / 2 {
+                        break Err(std::io::ErrorKind::TimedOut.into());
+                    }
+                    if self.last_sent.elapsed() >= self.keepalive / 2 {
+                        self.write(Packet::PingReq).await
+                    } else {
+                        Ok(())
+                    }
+                },
+            };
+
+            if let Err(error) = result {
+                break Err(error);
+            }
+        };
+
+        match result {
+            Ok(()) => tracing::debug!("Disconnected"),
+            Err(error) => tracing::warn!(%error, "Lost the connection to the MQTT broker"),
+        }
+    }
+
+    async fn handle_command(&mut self, command: Command) -> std::io::Result<()> {
+        match command {
+            Command::Publish(mut publish) => {
+                if publish.qos != Qos::AtMost {
+                    publish.packet_id = Some(self.packet_id());
+                }
+                self.write(Packet::Publish(publish)).await
+            }
+            Command::Subscribe {
+                filters,
+                messages,
+                subscribed,
+            } => {
+                let packet_id = self.packet_id();
+                // Subscribed right away, as the broker may publish before acknowledging
+                self.subscriptions.push(Subscription {
+                    filters: filters.clone(),
+                    messages,
+                });
+                self.pending_subscriptions.insert(packet_id, subscribed);
+
+                let filters = filters.into_iter().map(|f| (f, Qos::AtMost)).collect();
+                self.write(Packet::Subscribe { packet_id, filters }).await
+            }
+            Command::Disconnect(_) => unreachable!("Handled by the connection loop"),
+        }
+    }
+
+    async fn handle_packet(&mut self, packet: Packet) -> std::io::Result<()> {
+        tracing::trace!(?packet, "Received packet");
+        match packet {
+            Packet::Publish(publish) => match (publish.qos, publish.packet_id) {
+                (Qos::AtMost, _) => {
+                    self.deliver(publish);
+                    Ok(())
+                }
+                (Qos::AtLeast, Some(packet_id)) => {
+                    self.deliver(publish);
+                    self.write(Packet::PubAck(packet_id)).await
+                }
+                (Qos::Exactly, Some(packet_id)) => {
+                    if self.received_qos2.insert(packet_id) {
+                        self.deliver(publish);
+                    }
+                    self.write(Packet::PubRec(packet_id)).await
+                }
+                (_, None) => Err(std::io::ErrorKind::InvalidData.into()),
+            },
+            Packet::PubRec(packet_id) => self.write(Packet::PubRel(packet_id)).await,
+            Packet::PubRel(packet_id) => {
+                self.received_qos2.remove(&packet_id);
+                self.write(Packet::PubComp(packet_id)).await
+            }
+            Packet::SubAck {
+                packet_id,
+                return_codes,
+            } => {
+                if return_codes.contains(&0x80) {
+                    tracing::warn!(?return_codes, "The broker rejected a subscription");
+                }
+                if let Some(subscribed) = self.pending_subscriptions.remove(&packet_id) {
+                    let _ = subscribed.send(());
+                }
+                Ok(())
+            }
+            Packet::PubAck(_) | Packet::PubComp(_) | Packet::PingResp => Ok(()),
+            packet => {
+                tracing::warn!(?packet, "Ignoring unexpected packet");
+                Ok(())
+            }
+        }
+    }
+
+    /// Pass the message to all subscriptions with a matching filter
+    fn deliver(&mut self, publish: super::packet::Publish) {
+        self.subscriptions
+            .retain(|subscription| !subscription.messages.is_closed());
+
+        for subscription in self.subscriptions.iter() {
+            let matches = subscription
+                .filters
+                .iter()
+                .any(|filter| topic_matches(filter, &publish.topic));
+            if matches {
+                let _ = subscription.messages.unbounded_send(Message {
+                    topic: publish.topic.clone(),
+                    payload: publish.payload.clone(),
+                });
+            }
+        }
+    }
+
+    fn packet_id(&mut self) -> u16 {
+        // Packet ID 0 is not allowed
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        self.next_packet_id
+    }
+
+    async fn write(&mut self, packet: Packet) -> std::io::Result<()> {
+        tracing::trace!(?packet, "Sending packet");
+        self.writer.write_all(&packet.encode()).await?;
+        self.writer.flush().await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+}
+
+/// Whether the topic matches the filter, which can contain the wildcards `+` and `#`
+fn topic_matches(filter: &str, topic: &str) -> bool {
+    // Wildcards at the start do not match topics starting with '$'
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+
+    use super::Options;
+    use super::topic_matches;
+    use crate::mqtt::MqttError;
+    use crate::mqtt::Qos;
+    use crate::mqtt::packet::Connect;
+    use crate::mqtt::packet::Packet;
+    use crate::mqtt::packet::Publish;
+    use crate::mqtt::test_broker::TestBroker;
+
+    fn options(port: u16) -> Options {
+        Options {
+            host: String::from("127.0.0.1"),
+            port,
+            tls: None,
+            connect: Connect {
+                client_id: String::from("keypad"),
+                keepalive_secs: 30,
+                will: None,
+                username: None,
+                password: None,
+            },
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    fn incoming(topic: &str, qos: Qos, packet_id: Option<u16>) -> Packet {
+        Packet::Publish(Publish {
+            topic: String::from(topic),
+            payload: topic.as_bytes().to_vec(),
+            qos,
+            retain: false,
+            duplicate: false,
+            packet_id,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_receive_messages() {
+        let broker = TestBroker::bind().await;
+        let port = broker.port;
+        let broker = tokio::spawn(async move {
+            let (mut connection, _) = broker.accept(0).await;
+            assert_eq!(connection.accept_subscribe().await, ["keypad/+"]);
+
+            connection.write(incoming("other", Qos::AtMost, None)).await;
+            connection
+                .write(incoming("keypad/a", Qos::AtMost, None))
+                .await;
+            connection
+                .write(incoming("keypad/b", Qos::AtLeast, Some(7)))
+                .await;
+            assert_eq!(connection.read().await, Packet::PubAck(7));
+
+            // Sent again before it was released, which must not deliver it twice
+            for _ in 0..2 {
+                connection
+                    .write(incoming("keypad/c", Qos::Exactly, Some(8)))
+                    .await;
+                assert_eq!(connection.read().await, Packet::PubRec(8));
+            }
+            connection.write(Packet::PubRel(8)).await;
+            assert_eq!(connection.read().await, Packet::PubComp(8));
+            connection
+                .write(incoming("keypad/d", Qos::AtMost, None))
+                .await;
+
+            assert_eq!(connection.read().await, Packet::Disconnect);
+        });
+
+        let client = super::connect(options(port)).await.unwrap();
+        let mut messages = client.subscribe(vec![String::from("keypad/+")]).await;
+        let mut topics = Vec::new();
+        for _ in 0..4 {
+            topics.push(messages.next().await.unwrap().topic);
+        }
+        assert_eq!(topics, ["keypad/a", "keypad/b", "keypad/c", "keypad/d"]);
+
+        client.disconnect().await;
+        broker.await.unwrap();
+        // The connection is closed, which ends the subscription
+        assert!(messages.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        // Accepts TCP connections, but never answers CONNECT
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut options = options(listener.local_addr().unwrap().port());
+        options.timeout = Duration::from_millis(50);
+
+        let result = super::connect(options).await;
+        assert!(matches!(result, Err(MqttError::Timeout { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_connection_refused() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let result = super::connect(options(port)).await;
+        assert!(matches!(result, Err(MqttError::Connect { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_connection_lost() {
+        let broker = TestBroker::bind().await;
+        let client = super::connect(options(broker.port));
+        let ((mut connection, _), client) = tokio::join!(broker.accept(0), client);
+        let client = client.unwrap();
+
+        let subscribe = client.subscribe(vec![String::from("keypad/#")]);
+        let (_, mut messages) = tokio::join!(connection.accept_subscribe(), subscribe);
+        drop(connection);
+        assert!(messages.next().await.is_none());
+    }
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("keypad/control/all", "keypad/control/all"));
+        assert!(!topic_matches("keypad/control/all", "keypad/control"));
+        assert!(topic_matches("keypad/+/all", "keypad/control/all"));
+        assert!(!topic_matches("keypad/+", "keypad/control/all"));
+        assert!(topic_matches("keypad/#", "keypad/control/all"));
+        assert!(topic_matches("keypad/#", "keypad"));
+        assert!(topic_matches("#", "keypad"));
+        assert!(!topic_matches("#", "$SYS/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/uptime"));
+    }
+}
@@ -0,0 +1,425 @@
+//! Encoding and decoding of MQTT 3.1.1 control packets
+
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+
+use super::Qos;
+
+/// The largest remaining length that can be encoded in the fixed header
+const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+const PROTOCOL_NAME: &str = "MQTT";
+const PROTOCOL_LEVEL: u8 = 4;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PacketError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("Malformed packet: {0}")]
+    Malformed(&'static str),
+
+    #[error("Unknown packet type {0}")]
+    UnknownType(u8),
+}
+
+/// A message that the broker publishes if the client disconnects unexpectedly
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Will {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: Qos,
+    pub retain: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Connect {
+    pub client_id: String,
+    pub keepalive_secs: u16,
+    pub will: Option<Will>,
+    pub username: Option<String>,
+    pub password: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Publish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: Qos,
+    pub retain: bool,
+    pub duplicate: bool,
+    /// Only set for QoS 1 and 2
+    pub packet_id: Option<u16>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Packet {
+    Connect(Connect),
+    ConnAck {
+        session_present: bool,
+        return_code: u8,
+    },
+    Publish(Publish),
+    PubAck(u16),
+    PubRec(u16),
+    PubRel(u16),
+    PubComp(u16),
+    Subscribe {
+        packet_id: u16,
+        filters: Vec<(String, Qos)>,
+    },
+    SubAck {
+        packet_id: u16,
+        return_codes: Vec<u8>,
+    },
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+impl Packet {
+    /// The packet with its fixed header
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        let header = match self {
+            Packet::Connect(connect) => {
+                put_str(&mut body, PROTOCOL_NAME);
+                body.push(PROTOCOL_LEVEL);
+
+                let mut flags = 0x02; // Clean session
+                if let Some(will) = connect.will.as_ref() {
+                    flags |= 0x04 | (u8::from(will.qos) << 3);
+                    if will.retain {
+                        flags |= 0x20;
+                    }
+                }
+                if connect.password.is_some() {
+                    flags |= 0x40;
+                }
+                if connect.username.is_some() {
+                    flags |= 0x80;
+                }
+                body.push(flags);
+                body.extend_from_slice(&connect.keepalive_secs.to_be_bytes());
+
+                put_str(&mut body, &connect.client_id);
+                if let Some(will) = connect.will.as_ref() {
+                    put_str(&mut body, &will.topic);
+                    put_bytes(&mut body, &will.payload);
+                }
+                if let Some(username) = connect.username.as_deref() {
+                    put_str(&mut body, username);
+                }
+                if let Some(password) = connect.password.as_deref() {
+                    put_bytes(&mut body, password);
+                }
+                0x10
+            }
+            Packet::ConnAck {
+                session_present,
+                return_code,
+            } => {
+                body.extend_from_slice(&[u8::from(*session_present), *return_code]);
+                0x20
+            }
+            Packet::Publish(publish) => {
+                put_str(&mut body, &publish.topic);
+                if let Some(packet_id) = publish.packet_id {
+                    body.extend_from_slice(&packet_id.to_be_bytes());
+                }
+                body.extend_from_slice(&publish.payload);
+
+                0x30 | (u8::from(publish.duplicate) << 3)
+                    | (u8::from(publish.qos) << 1)
+                    | u8::from(publish.retain)
+            }
+            Packet::PubAck(packet_id) => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                0x40
+            }
+            Packet::PubRec(packet_id) => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                0x50
+            }
+            Packet::PubRel(packet_id) => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                0x62
+            }
+            Packet::PubComp(packet_id) => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                0x70
+            }
+            Packet::Subscribe { packet_id, filters } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                for (filter, qos) in filters.iter() {
+                    put_str(&mut body, filter);
+                    body.push(u8::from(*qos));
+                }
+                0x82
+            }
+            Packet::SubAck {
+                packet_id,
+                return_codes,
+            } => {
+                body.extend_from_slice(&packet_id.to_be_bytes());
+                body.extend_from_slice(return_codes);
+                0x90
+            }
+            Packet::PingReq => 0xc0,
+            Packet::PingResp => 0xd0,
+            Packet::Disconnect => 0xe0,
+        };
+
+        let mut packet = Vec::with_capacity(body.len() + 5);
+        packet.push(header);
+        let mut length = body.len();
+        loop {
+            let mut byte = (length % 128) as u8;
+            length /= 128;
+            if length > 0 {
+                byte |= 0x80;
+            }
+            packet.push(byte);
+            if length == 0 {
+                break;
+            }
+        }
+        packet.extend_from_slice(&body);
+        packet
+    }
+
+    /// Decode a packet from its fixed header byte and its body
+    pub fn decode(header: u8, body: &[u8]) -> Result<Self, PacketError> {
+        let flags = header & 0x0f;
+        let mut body = Reader(body);
+        let packet = match header >> 4 {
+            1 => {
+                if body.str()? != PROTOCOL_NAME || body.u8()? != PROTOCOL_LEVEL {
+                    return Err(PacketError::Malformed("unsupported protocol"));
+                }
+                let flags = body.u8()?;
+                let keepalive_secs = body.u16()?;
+                let client_id = body.str()?;
+                let will = if flags & 0x04 != 0 {
+                    Some(Will {
+                        topic: body.str()?,
+                        payload: body.bytes()?,
+                        qos: qos((flags >> 3) & 0x03)?,
+                        retain: flags & 0x20 != 0,
+                    })
+                } else {
+                    None
+                };
+                let username = (flags & 0x80 != 0).then(|| body.str()).transpose()?;
+                let password = (flags & 0x40 != 0).then(|| body.bytes()).transpose()?;
+                Packet::Connect(Connect {
+                    client_id,
+                    keepalive_secs,
+                    will,
+                    username,
+                    password,
+                })
+            }
+            2 => Packet::ConnAck {
+                session_present: body.u8()? & 0x01 != 0,
+                return_code: body.u8()?,
+            },
+            3 => {
+                let qos = qos((flags >> 1) & 0x03)?;
+                let topic = body.str()?;
+                let packet_id = (qos != Qos::AtMost).then(|| body.u16()).transpose()?;
+                Packet::Publish(Publish {
+                    topic,
+                    payload: body.rest().to_vec(),
+                    qos,
+                    retain: flags & 0x01 != 0,
+                    duplicate: flags & 0x08 != 0,
+                    packet_id,
+                })
+            }
+            4 => Packet::PubAck(body.u16()?),
+            5 => Packet::PubRec(body.u16()?),
+            6 => Packet::PubRel(body.u16()?),
+            7 => Packet::PubComp(body.u16()?),
+            8 => {
+                let packet_id = body.u16()?;
+                let mut filters = Vec::new();
+                while !body.0.is_empty() {
+                    filters.push((body.str()?, qos(body.u8()?)?));
+                }
+                Packet::Subscribe { packet_id, filters }
+            }
+            9 => Packet::SubAck {
+                packet_id: body.u16()?,
+                return_codes: body.rest().to_vec(),
+            },
+            12 => Packet::PingReq,
+            13 => Packet::PingResp,
+            14 => Packet::Disconnect,
+            packet_type => return Err(PacketError::UnknownType(packet_type)),
+        };
+        Ok(packet)
+    }
+}
+
+/// Read the next packet from the stream
+pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Packet, PacketError> {
+    let header = reader.read_u8().await?;
+
+    let mut length = 0usize;
+    let mut multiplier = 1usize;
+    loop {
+        let byte = reader.read_u8().await?;
+        length += usize::from(byte & 0x7f) * multiplier;
+        if length > MAX_REMAINING_LENGTH {
+            return Err(PacketError::Malformed("remaining length too large"));
+        }
+        if byte & 0x80 == 0 {
+            break;
+        }
+        multiplier *= 128;
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Packet::decode(header, &body)
+}
+
+fn qos(value: u8) -> Result<Qos, PacketError> {
+    Qos::try_from(value).map_err(|_| PacketError::Malformed("invalid QoS level"))
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    // Longer strings cannot be encoded, the broker rejects the truncated packet
+    let length = u16::try_from(bytes.len()).unwrap_or(u16::MAX);
+    buffer.extend_from_slice(&length.to_be_bytes());
+    buffer.extend_from_slice(&bytes[..usize::from(length)]);
+}
+
+fn put_str(buffer: &mut Vec<u8>, s: &str) {
+    put_bytes(buffer, s.as_bytes())
+}
+
+/// Reads the fields of a packet body
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], PacketError> {
+        if self.0.len() < n {
+            return Err(PacketError::Malformed("packet too short"));
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, PacketError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, PacketError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>, PacketError> {
+        let length = self.u16()?;
+        Ok(self.take(usize::from(length))?.to_vec())
+    }
+
+    fn str(&mut self) -> Result<String, PacketError> {
+        String::from_utf8(self.bytes()?).map_err(|_| PacketError::Malformed("invalid UTF-8"))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Connect;
+    use super::Packet;
+    use super::Publish;
+    use super::Will;
+    use crate::mqtt::Qos;
+
+    async fn round_trip(packet: Packet) -> Packet {
+        let bytes = packet.encode();
+        super::read(&mut bytes.as_slice()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let packets = [
+            Packet::Connect(Connect {
+                client_id: String::from("keypad"),
+                keepalive_secs: 30,
+                will: Some(Will {
+                    topic: String::from("keypad/availability"),
+                    payload: b"offline".to_vec(),
+                    qos: Qos::AtLeast,
+                    retain: true,
+                }),
+                username: Some(String::from("user")),
+                password: Some(b"secret".to_vec()),
+            }),
+            Packet::ConnAck {
+                session_present: false,
+                return_code: 5,
+            },
+            Packet::Publish(Publish {
+                topic: String::from("keypad/state"),
+                // Long enough to need two bytes for the remaining length
+                payload: vec![7; 300],
+                qos: Qos::Exactly,
+                retain: true,
+                duplicate: false,
+                packet_id: Some(42),
+            }),
+            Packet::PubRel(42),
+            Packet::Subscribe {
+                packet_id: 1,
+                filters: vec![(String::from("keypad/#"), Qos::AtMost)],
+            },
+            Packet::SubAck {
+                packet_id: 1,
+                return_codes: vec![0, 0x80],
+            },
+            Packet::PingReq,
+            Packet::Disconnect,
+        ];
+
+        for packet in packets {
+            assert_eq!(round_trip(packet.clone()).await, packet);
+        }
+    }
+
+    #[test]
+    fn test_encode_publish() {
+        let publish = Packet::Publish(Publish {
+            topic: String::from("a/b"),
+            payload: b"on".to_vec(),
+            qos: Qos::AtMost,
+            retain: true,
+            duplicate: false,
+            packet_id: None,
+        });
+        assert_eq!(
+            publish.encode(),
+            [0x31, 7, 0, 3, b'a', b'/', b'b', b'o', b'n']
+        );
+    }
+
+    #[tokio::test]
+    async fn test_malformed_packets() {
+        let truncated: &[u8] = &[0x20, 1, 0];
+        assert!(super::read(&mut &truncated[..]).await.is_err());
+
+        let unknown: &[u8] = &[0xf0, 0];
+        assert!(matches!(
+            super::read(&mut &unknown[..]).await,
+            Err(super::PacketError::UnknownType(15))
+        ));
+    }
+}
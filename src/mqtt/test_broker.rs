@@ -0,0 +1,91 @@
+//! A stand-in broker for tests, which lets the test drive every packet
+
+use tokio::io::AsyncWriteExt;
+
+use super::packet::Connect;
+use super::packet::Packet;
+
+pub struct TestBroker {
+    listener: tokio::net::TcpListener,
+    pub port: u16,
+}
+
+impl TestBroker {
+    pub async fn bind() -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        Self { listener, port }
+    }
+
+    /// Connect a client without any options and accept it
+    pub async fn connect(&self) -> (BrokerConnection, super::Client) {
+        let options = super::client::Options {
+            host: String::from("127.0.0.1"),
+            port: self.port,
+            tls: None,
+            connect: Connect {
+                client_id: String::new(),
+                keepalive_secs: 0,
+                will: None,
+                username: None,
+                password: None,
+            },
+            timeout: std::time::Duration::from_secs(10),
+        };
+        let ((connection, _), client) =
+            tokio::join!(self.accept(0), super::client::connect(options));
+        (connection, client.unwrap())
+    }
+
+    /// Accept the next client and answer its CONNECT packet with the return code
+    pub async fn accept(&self, return_code: u8) -> (BrokerConnection, Connect) {
+        let (stream, _) = self.listener.accept().await.unwrap();
+        let mut connection = BrokerConnection { stream };
+        let Packet::Connect(connect) = connection.read().await else {
+            panic!("Expected CONNECT");
+        };
+        connection
+            .write(Packet::ConnAck {
+                session_present: false,
+                return_code,
+            })
+            .await;
+        (connection, connect)
+    }
+}
+
+pub struct BrokerConnection {
+    stream: tokio::net::TcpStream,
+}
+
+impl BrokerConnection {
+    pub async fn read(&mut self) -> Packet {
+        super::packet::read(&mut self.stream).await.unwrap()
+    }
+
+    pub async fn write(&mut self, packet: Packet) {
+        self.stream.write_all(&packet.encode()).await.unwrap();
+    }
+
+    /// Acknowledge the next SUBSCRIBE packet, returning its filters
+    pub async fn accept_subscribe(&mut self) -> Vec<String> {
+        let Packet::Subscribe { packet_id, filters } = self.read().await else {
+            panic!("Expected SUBSCRIBE");
+        };
+        let return_codes = vec![0; filters.len()];
+        self.write(Packet::SubAck {
+            packet_id,
+            return_codes,
+        })
+        .await;
+        filters.into_iter().map(|(filter, _)| filter).collect()
+    }
+
+    /// Read the next PUBLISH packet
+    pub async fn read_publish(&mut self) -> super::packet::Publish {
+        match self.read().await {
+            Packet::Publish(publish) => publish,
+            packet => panic!("Expected PUBLISH, got {packet:?}"),
+        }
+    }
+}
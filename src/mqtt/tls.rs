@@ -0,0 +1,132 @@
+use std::sync::Arc;
+
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::PrivateKeyDer;
+use rustls::pki_types::pem::PemObject;
+
+use super::MqttError;
+
+/// Build the TLS configuration, reading all configured files
+pub async fn client_config(
+    tls: &crate::config::TlsConfig,
+) -> Result<Arc<rustls::ClientConfig>, MqttError> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(MqttError::Tls)?;
+
+    let builder = if tls.insecure {
+        tracing::warn!("Not verifying the certificate of the MQTT broker");
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(InsecureVerifier(provider)))
+    } else {
+        let mut roots = rustls::RootCertStore::empty();
+        match tls.ca_file.as_ref() {
+            Some(path) => {
+                for certificate in read_certificates(path).await? {
+                    roots.add(certificate).map_err(MqttError::Tls)?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        builder.with_root_certificates(roots)
+    };
+
+    let config = match (
+        tls.client_certificate_file.as_ref(),
+        tls.client_key_file.as_ref(),
+    ) {
+        (Some(certificate_path), Some(key_path)) => {
+            let certificates = read_certificates(certificate_path).await?;
+            let key = PrivateKeyDer::from_pem_slice(&read(key_path).await?).map_err(|source| {
+                MqttError::InvalidTlsFile {
+                    path: key_path.clone(),
+                    source,
+                }
+            })?;
+            builder
+                .with_client_auth_cert(certificates, key)
+                .map_err(MqttError::Tls)?
+        }
+        _ => builder.with_no_client_auth(),
+    };
+
+    Ok(Arc::new(config))
+}
+
+async fn read(path: &camino::Utf8Path) -> Result<Vec<u8>, MqttError> {
+    tokio::fs::read(path)
+        .await
+        .map_err(|source| MqttError::TlsFile {
+            path: path.to_path_buf(),
+            source,
+        })
+}
+
+async fn read_certificates(
+    path: &camino::Utf8Path,
+) -> Result<Vec<CertificateDer<'static>>, MqttError> {
+    let invalid = |source| MqttError::InvalidTlsFile {
+        path: path.to_path_buf(),
+        source,
+    };
+
+    let certificates = CertificateDer::pem_slice_iter(&read(path).await?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    if certificates.is_empty() {
+        return Err(invalid(rustls::pki_types::pem::Error::NoItemsFound));
+    }
+
+    Ok(certificates)
+}
+
+/// Accepts any certificate, but still checks that the broker owns it
+#[derive(Debug)]
+struct InsecureVerifier(Arc<rustls::crypto::CryptoProvider>);
+
+impl rustls::client::danger::ServerCertVerifier for InsecureVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &rustls::pki_types::ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::danger::ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,82 @@
+use camino::Utf8Path;
+use camino::Utf8PathBuf;
+use notify::Watcher;
+use tokio::signal::unix::Signal;
+use tokio::signal::unix::SignalKind;
+
+#[derive(Debug)]
+pub enum ReloadTrigger {
+    FileChanged,
+    Hangup,
+}
+
+/// Notifies about reasons to reload the configuration file
+pub struct ReloadTriggers {
+    // Kept so that the watcher is not dropped, which would stop watching
+    _watcher: notify::RecommendedWatcher,
+    file_changes: tokio::sync::mpsc::Receiver<()>,
+    hangup: Signal,
+}
+
+impl ReloadTriggers {
+    pub fn new(config_path: &Utf8Path) -> Result<Self, ReloadError> {
+        let (sender, file_changes) = tokio::sync::mpsc::channel(1);
+
+        let file_name = config_path.file_name().map(ToString::to_string);
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(error) => {
+                        tracing::warn!(?error, "Error while watching configuration file");
+                        return;
+                    }
+                };
+
+                if !(event.kind.is_modify() || event.kind.is_create()) {
+                    return;
+                }
+
+                let concerns_config = event
+                    .paths
+                    .iter()
+                    .any(|path| path.file_name().and_then(|n| n.to_str()) == file_name.as_deref());
+
+                if concerns_config {
+                    // A full channel means that a reload is pending already
+                    let _ = sender.try_send(());
+                }
+            })?;
+
+        // Watch the directory, as editors tend to replace the file instead of writing to it
+        let directory = config_path
+            .parent()
+            .filter(|p| !p.as_str().is_empty())
+            .map(Utf8Path::to_path_buf)
+            .unwrap_or_else(|| Utf8PathBuf::from("."));
+        watcher.watch(directory.as_std_path(), notify::RecursiveMode::NonRecursive)?;
+
+        Ok(Self {
+            _watcher: watcher,
+            file_changes,
+            hangup: tokio::signal::unix::signal(SignalKind::hangup())?,
+        })
+    }
+
+    pub async fn next(&mut self) -> ReloadTrigger {
+        tokio::select! {
+            Some(()) = self.file_changes.recv() => ReloadTrigger::FileChanged,
+            Some(()) = self.hangup.recv() => ReloadTrigger::Hangup,
+            else => std::future::pending().await,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReloadError {
+    #[error("Failed to watch configuration file")]
+    Watch(#[from] notify::Error),
+
+    #[error("Failed to listen for SIGHUP")]
+    Signal(#[from] std::io::Error),
+}
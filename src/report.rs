@@ -0,0 +1,41 @@
+/// The state of a single key, published on `{mqtt_state_prefix}/state/key/{index}`
+#[derive(Debug, serde::Serialize)]
+pub struct KeyReport<'a> {
+    pub index: u8,
+    pub row: u8,
+    pub col: u8,
+    pub label: Option<&'a str>,
+    pub page: &'a str,
+    pub pressed: bool,
+    pub blinking: bool,
+    pub alternative_color: bool,
+    pub colors: ColorsReport,
+}
+
+/// The colors of a key, including those set by control actions and state topics
+#[derive(Debug, serde::Serialize)]
+pub struct ColorsReport {
+    pub released: crate::util::Rgb,
+    pub pressed: crate::util::Rgb,
+    pub alternative: crate::util::Rgb,
+}
+
+/// The state of the whole keypad, published on `{mqtt_state_prefix}/state/keypad`
+#[derive(Debug, serde::Serialize)]
+pub struct KeypadReport<'a> {
+    pub active_page: &'a str,
+    pub page_stack: Vec<&'a str>,
+    /// The brightness in percent, as set by actions, without night mode
+    pub brightness: u8,
+    pub keys: Vec<KeyReport<'a>>,
+}
+
+/// The topic that the state of the key with the passed index is published on
+pub fn key_topic(state_prefix: &str, index: u8) -> String {
+    format!("{state_prefix}/state/key/{index}")
+}
+
+/// The topic that the state of the whole keypad is published on
+pub fn keypad_topic(state_prefix: &str) -> String {
+    format!("{state_prefix}/state/keypad")
+}
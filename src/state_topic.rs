@@ -0,0 +1,192 @@
+/// Maps the state published on an MQTT topic to colors of a key
+#[derive(Clone, Debug)]
+pub struct StateTopic {
+    pub topic: String,
+    json_path: Option<String>,
+    mapping: Vec<StateMapping>,
+}
+
+#[derive(Clone, Debug)]
+struct StateMapping {
+    equals: Option<String>,
+    min: Option<f64>,
+    max: Option<f64>,
+    colors: StateColors,
+}
+
+/// Colors that replace the configured colors of a key
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StateColors {
+    pub released: Option<crate::util::Rgb>,
+    pub pressed: Option<crate::util::Rgb>,
+}
+
+impl From<&crate::config::StateTopicConfig> for StateTopic {
+    fn from(config: &crate::config::StateTopicConfig) -> Self {
+        Self {
+            topic: config.topic.to_string(),
+            json_path: config.json_path.clone(),
+            mapping: config
+                .mapping
+                .iter()
+                .map(|mapping| StateMapping {
+                    equals: mapping.equals.clone(),
+                    min: mapping.min,
+                    max: mapping.max,
+                    colors: StateColors {
+                        released: mapping.released,
+                        pressed: mapping.pressed,
+                    },
+                })
+                .collect(),
+        }
+    }
+}
+
+impl StateTopic {
+    /// The colors for a payload published on the state topic
+    ///
+    /// Returns the default colors if the payload cannot be understood or no mapping matches.
+    pub fn colors(&self, payload: &[u8]) -> StateColors {
+        let Some(value) = self.extract_value(payload) else {
+            return StateColors::default();
+        };
+
+        self.mapping
+            .iter()
+            .find(|mapping| mapping.matches(&value))
+            .map(|mapping| mapping.colors)
+            .unwrap_or_default()
+    }
+
+    fn extract_value(&self, payload: &[u8]) -> Option<String> {
+        let payload = match std::str::from_utf8(payload) {
+            Ok(payload) => payload.trim(),
+            Err(error) => {
+                tracing::warn!(topic = self.topic, ?error, "State payload is not UTF-8");
+                return None;
+            }
+        };
+
+        let Some(json_path) = self.json_path.as_ref() else {
+            return Some(payload.to_string());
+        };
+
+        let json = match serde_json::from_str::<serde_json::Value>(payload) {
+            Ok(json) => json,
+            Err(error) => {
+                tracing::warn!(topic = self.topic, ?error, "State payload is not JSON");
+                return None;
+            }
+        };
+
+        let value = json_path
+            .split('.')
+            .try_fold(&json, |value, segment| match value {
+                serde_json::Value::Array(array) => segment
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|index| array.get(index)),
+                value => value.get(segment),
+            });
+
+        match value {
+            Some(serde_json::Value::String(s)) => Some(s.to_string()),
+            Some(value) => Some(value.to_string()),
+            None => {
+                tracing::warn!(
+                    topic = self.topic,
+                    json_path,
+                    "Path not found in state payload"
+                );
+                None
+            }
+        }
+    }
+}
+
+impl StateMapping {
+    fn matches(&self, value: &str) -> bool {
+        if self.equals.as_ref().is_some_and(|equals| equals != value) {
+            return false;
+        }
+
+        if self.min.is_none() && self.max.is_none() {
+            return true;
+        }
+
+        let Ok(number) = value.parse::<f64>() else {
+            return false;
+        };
+
+        self.min.is_none_or(|min| number >= min) && self.max.is_none_or(|max| number < max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StateTopic;
+
+    fn state_topic(config: &str) -> StateTopic {
+        let config: crate::config::StateTopicConfig = toml::from_str(config).unwrap();
+        StateTopic::from(&config)
+    }
+
+    #[test]
+    fn test_exact_match() {
+        let state_topic = state_topic(
+            r#"
+            topic = "light/state"
+            [[mapping]]
+            equals = "ON"
+            released = [0, 50, 0]
+            "#,
+        );
+
+        let colors = state_topic.colors(b"ON");
+        assert_eq!(colors.released.unwrap().as_slice(), [0, 50, 0]);
+        assert!(colors.pressed.is_none());
+
+        assert!(state_topic.colors(b"OFF").released.is_none());
+    }
+
+    #[test]
+    fn test_json_path_with_ranges() {
+        let state_topic = state_topic(
+            r#"
+            topic = "sensor/state"
+            json_path = "attributes.values.1"
+            [[mapping]]
+            max = 10
+            released = [0, 0, 50]
+            [[mapping]]
+            min = 10
+            max = 20
+            released = [0, 50, 0]
+            [[mapping]]
+            released = [50, 0, 0]
+            "#,
+        );
+
+        let colors = |payload: &str| {
+            state_topic
+                .colors(payload.as_bytes())
+                .released
+                .map(|c| c.as_slice())
+        };
+        assert_eq!(
+            colors(r#"{"attributes": {"values": [0, 5.5]}}"#),
+            Some([0, 0, 50])
+        );
+        assert_eq!(
+            colors(r#"{"attributes": {"values": [0, 10]}}"#),
+            Some([0, 50, 0])
+        );
+        assert_eq!(
+            colors(r#"{"attributes": {"values": [0, "high"]}}"#),
+            Some([50, 0, 0])
+        );
+        assert_eq!(colors(r#"{"attributes": {}}"#), None);
+        assert_eq!(colors("not json"), None);
+    }
+}
@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+
+#[derive(Debug, thiserror::Error, miette::Diagnostic)]
+pub enum TemplateError {
+    #[error("Unknown template variable '{variable}' in '{template}'")]
+    #[diagnostic(help("Known variables are: {known}"))]
+    UnknownVariable {
+        template: String,
+        variable: String,
+        known: String,
+    },
+
+    #[error("Unclosed template variable in '{0}'")]
+    Unclosed(String),
+}
+
+/// Replace all `{{variable}}` placeholders in the template with the values of the variables
+pub fn render(template: &str, variables: &BTreeMap<&str, String>) -> Result<String, TemplateError> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+
+        let after_start = &rest[start + 2..];
+        let Some(end) = after_start.find("}}") else {
+            return Err(TemplateError::Unclosed(template.to_string()));
+        };
+
+        let variable = after_start[..end].trim();
+        let Some(value) = variables.get(variable) else {
+            return Err(TemplateError::UnknownVariable {
+                template: template.to_string(),
+                variable: variable.to_string(),
+                known: variables.keys().copied().collect::<Vec<_>>().join(", "),
+            });
+        };
+        rendered.push_str(value);
+
+        rest = &after_start[end + 2..];
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::TemplateError;
+    use super::render;
+
+    fn variables() -> BTreeMap<&'static str, String> {
+        BTreeMap::from([
+            ("index", String::from("7")),
+            ("duration_ms", String::from("1200")),
+        ])
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render(
+                r#"{"key": {{index}}, "held_ms": {{ duration_ms }}}"#,
+                &variables()
+            )
+            .unwrap(),
+            r#"{"key": 7, "held_ms": 1200}"#
+        );
+        assert_eq!(
+            render("keypad/{{index}}/pressed", &variables()).unwrap(),
+            "keypad/7/pressed"
+        );
+        assert_eq!(render("static", &variables()).unwrap(), "static");
+    }
+
+    #[test]
+    fn test_render_errors() {
+        assert!(matches!(
+            render("{{nope}}", &variables()),
+            Err(TemplateError::UnknownVariable { variable, .. }) if variable == "nope"
+        ));
+        assert!(matches!(
+            render("{{index", &variables()),
+            Err(TemplateError::Unclosed(_))
+        ));
+    }
+}
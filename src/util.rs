@@ -1,14 +1,208 @@
-#[derive(Clone, Copy, Debug)]
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// A color, deserialized from `[r, g, b]`, `"#rrggbb"`, `"#rgb"`, a named color like `"orange"`,
+/// a `{ h, s, v }` table or the name of a color in the palette, see [`with_palette`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize)]
+#[serde(transparent)]
 pub struct Rgb([u8; 3]);
 
+/// CSS-like color names
+const NAMED_COLORS: [(&str, [u8; 3]); 27] = [
+    ("black", [0, 0, 0]),
+    ("white", [255, 255, 255]),
+    ("gray", [128, 128, 128]),
+    ("grey", [128, 128, 128]),
+    ("silver", [192, 192, 192]),
+    ("red", [255, 0, 0]),
+    ("maroon", [128, 0, 0]),
+    ("orange", [255, 165, 0]),
+    ("gold", [255, 215, 0]),
+    ("yellow", [255, 255, 0]),
+    ("olive", [128, 128, 0]),
+    ("lime", [0, 255, 0]),
+    ("green", [0, 128, 0]),
+    ("teal", [0, 128, 128]),
+    ("cyan", [0, 255, 255]),
+    ("aqua", [0, 255, 255]),
+    ("turquoise", [64, 224, 208]),
+    ("blue", [0, 0, 255]),
+    ("navy", [0, 0, 128]),
+    ("indigo", [75, 0, 130]),
+    ("purple", [128, 0, 128]),
+    ("violet", [238, 130, 238]),
+    ("magenta", [255, 0, 255]),
+    ("fuchsia", [255, 0, 255]),
+    ("pink", [255, 192, 203]),
+    ("brown", [165, 42, 42]),
+    ("coral", [255, 127, 80]),
+];
+
+thread_local! {
+    /// The palette that color names are looked up in while deserializing
+    static PALETTE: RefCell<BTreeMap<String, Rgb>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Run `f` with the palette, so that colors deserialized by it can refer to palette entries by
+/// name
+///
+/// Palette entries take precedence over named colors.
+pub fn with_palette<T>(palette: BTreeMap<String, Rgb>, f: impl FnOnce() -> T) -> T {
+    let previous = PALETTE.replace(palette);
+    let result = f();
+    PALETTE.set(previous);
+    result
+}
+
 impl From<[u8; 3]> for Rgb {
     fn from(value: [u8; 3]) -> Self {
         Self(value)
     }
 }
 
+impl std::str::FromStr for Rgb {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(hex) = s.strip_prefix('#') {
+            let digits = hex
+                .chars()
+                .map(|c| c.to_digit(16).map(|digit| digit as u8))
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| format!("Invalid hex color '{s}'"))?;
+
+            let byte = |high: u8, low: u8| high * 16 + low;
+            return match digits[..] {
+                [r, g, b] => Ok(Rgb([byte(r, r), byte(g, g), byte(b, b)])),
+                [r1, r2, g1, g2, b1, b2] => Ok(Rgb([byte(r1, r2), byte(g1, g2), byte(b1, b2)])),
+                _ => Err(format!("Hex color '{s}' must have 3 or 6 digits")),
+            };
+        }
+
+        if let Some(color) = PALETTE.with_borrow(|palette| palette.get(s).copied()) {
+            return Ok(color);
+        }
+
+        let named = NAMED_COLORS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s));
+        match named {
+            Some((_, color)) => Ok(Rgb(*color)),
+            None => Err(format!(
+                "Unknown color '{s}', neither a named color nor in the palette"
+            )),
+        }
+    }
+}
+
+/// A color given as hue in degrees, saturation and value between 0 and 1
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Hsv {
+    h: f32,
+    s: f32,
+    v: f32,
+}
+
+impl TryFrom<Hsv> for Rgb {
+    type Error = String;
+
+    fn try_from(Hsv { h, s, v }: Hsv) -> Result<Self, Self::Error> {
+        if !(0.0..=1.0).contains(&s) || !(0.0..=1.0).contains(&v) {
+            return Err(format!(
+                "Saturation {s} and value {v} must be between 0 and 1"
+            ));
+        }
+
+        let hue = Rgb::from_hue(h / 360.0, 255);
+        let white = Rgb([255, 255, 255]);
+        Ok(white.mix(hue, s).scale(v))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Rgb {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct RgbVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for RgbVisitor {
+            type Value = Rgb;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("[r, g, b], a hex string, a color name or { h, s, v }")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, value: &str) -> Result<Rgb, E> {
+                value.parse().map_err(E::custom)
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(self, seq: A) -> Result<Rgb, A::Error> {
+                let deserializer = serde::de::value::SeqAccessDeserializer::new(seq);
+                <[u8; 3] as serde::Deserialize>::deserialize(deserializer).map(Rgb)
+            }
+
+            fn visit_map<A: serde::de::MapAccess<'de>>(self, map: A) -> Result<Rgb, A::Error> {
+                let deserializer = serde::de::value::MapAccessDeserializer::new(map);
+                let hsv = <Hsv as serde::Deserialize>::deserialize(deserializer)?;
+                Rgb::try_from(hsv).map_err(serde::de::Error::custom)
+            }
+        }
+
+        deserializer.deserialize_any(RgbVisitor)
+    }
+}
+
 impl Rgb {
     pub fn as_slice(&self) -> [u8; 3] {
         self.0
     }
+
+    /// Blend towards the other color, from this color at 0 to the other color at 1
+    pub fn mix(self, other: Rgb, t: f32) -> Rgb {
+        let t = t.clamp(0.0, 1.0);
+        let channel =
+            |a: u8, b: u8| (f32::from(a) + (f32::from(b) - f32::from(a)) * t).round() as u8;
+        Rgb([
+            channel(self.0[0], other.0[0]),
+            channel(self.0[1], other.0[1]),
+            channel(self.0[2], other.0[2]),
+        ])
+    }
+
+    /// Scale the brightness by a factor between 0 and 1
+    pub fn scale(self, factor: f32) -> Rgb {
+        Rgb([0, 0, 0]).mix(self, factor)
+    }
+
+    /// Correct the color for the non-linear brightness perception, with a gamma of 1 keeping it
+    pub fn gamma(self, gamma: f32) -> Rgb {
+        let channel = |c: u8| ((f32::from(c) / 255.0).powf(gamma) * 255.0).round() as u8;
+        Rgb([channel(self.0[0]), channel(self.0[1]), channel(self.0[2])])
+    }
+
+    /// The fully saturated color of a hue between 0 and 1, at the passed brightness
+    pub fn from_hue(hue: f32, brightness: u8) -> Rgb {
+        let h = hue.rem_euclid(1.0) * 6.0;
+        let x = 1.0 - (h % 2.0 - 1.0).abs();
+        let [r, g, b] = match h as u8 {
+            0 => [1.0, x, 0.0],
+            1 => [x, 1.0, 0.0],
+            2 => [0.0, 1.0, x],
+            3 => [0.0, x, 1.0],
+            4 => [x, 0.0, 1.0],
+            _ => [1.0, 0.0, x],
+        };
+        let channel = |c: f32| (c * f32::from(brightness)).round() as u8;
+        Rgb([channel(r), channel(g), channel(b)])
+    }
+}
+
+/// Sleep until the deadline, or forever if there is none
+pub async fn sleep_until(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
 }